@@ -0,0 +1,361 @@
+use crate::{mentions_type_params, parse_metas, push_predicate, ParamBounds};
+use proc_macro2::TokenStream;
+use quote::{format_ident, quote, ToTokens};
+use std::collections::HashSet;
+use syn::{
+    parse_quote, spanned::Spanned, Attribute, Data, DeriveInput, Error, Field, Fields, Generics,
+    Ident, Lit, Meta, NestedMeta, Path, Type,
+};
+
+#[derive(Debug, Clone)]
+struct ContainerAttr {
+    /// The path to this crate, set by `#[ivalue(crate = "...")]`.
+    pub crate_path: TokenStream,
+    /// The path to the tch crate, set by `#[ivalue(tch = "...")]`.
+    pub tch_path: TokenStream,
+    /// Converts to a dictionary keyed by field names, set by `#[ivalue(dict)]`.
+    pub dict: bool,
+}
+
+#[derive(Debug, Clone)]
+struct FieldAttr {
+    /// Leaves the field out of the value and fills it with `Default::default()`.
+    /// `PhantomData` fields are always skipped.
+    pub skip: bool,
+    /// The dictionary key, set by `#[ivalue(rename = "...")]`.
+    pub rename: Option<String>,
+}
+
+pub fn parse_into_ivalue(input: DeriveInput) -> Result<TokenStream, Error> {
+    let fields = struct_fields(&input, "IntoIValue")?;
+    let container = parse_container_attrs(&input.attrs)?;
+    let ContainerAttr {
+        crate_path,
+        tch_path,
+        dict,
+    } = &container;
+    let field_attrs = parse_all_field_attrs(fields)?;
+
+    let name = &input.ident;
+    let param_bounds = ParamBounds {
+        value: quote! { #crate_path::IntoIValue },
+        hash_key: quote! { #crate_path::IntoIValue },
+        ord_key: quote! { #crate_path::IntoIValue },
+        error: quote! { #crate_path::IntoIValue },
+    };
+    let generics = add_trait_bounds(input.generics.clone(), fields, &field_attrs, &param_bounds);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let bindings: Vec<_> = fields
+        .iter()
+        .zip(&field_attrs)
+        .enumerate()
+        .map(|(index, (field, field_attr))| {
+            if field_attr.skip {
+                quote! { _ }
+            } else {
+                format_ident!("_{}", index, span = field.ty.span()).into_token_stream()
+            }
+        })
+        .collect();
+    let pattern = match fields {
+        Fields::Named(named_fields) => {
+            let field_names = named_fields.named.iter().map(|field| &field.ident);
+            quote! { Self { #(#field_names: #bindings),* } }
+        }
+        Fields::Unnamed(_) => quote! { Self(#(#bindings),*) },
+        Fields::Unit => quote! { Self },
+    };
+
+    let values = fields
+        .iter()
+        .zip(&field_attrs)
+        .zip(&bindings)
+        .filter(|((_, field_attr), _)| !field_attr.skip)
+        .map(|((field, field_attr), binding)| {
+            let value = quote! { #crate_path::IntoIValue::into_ivalue(#binding) };
+
+            if *dict {
+                let key = dict_key(field, field_attr)?;
+                Ok(quote! {
+                    (
+                        #tch_path::IValue::String(::std::string::String::from(#key)),
+                        #value,
+                    )
+                })
+            } else {
+                Ok(value)
+            }
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let expanded_value = if *dict {
+        quote! { #tch_path::IValue::GenericDict(::std::vec![#(#values),*]) }
+    } else {
+        quote! { #tch_path::IValue::Tuple(::std::vec![#(#values),*]) }
+    };
+
+    Ok(quote! {
+        impl #impl_generics #crate_path::IntoIValue for #name #ty_generics #where_clause {
+            fn into_ivalue(self) -> #tch_path::IValue {
+                let #pattern = self;
+                #expanded_value
+            }
+        }
+    })
+}
+
+pub fn parse_try_from_ivalue(input: DeriveInput) -> Result<TokenStream, Error> {
+    let fields = struct_fields(&input, "TryFromIValue")?;
+    let container = parse_container_attrs(&input.attrs)?;
+    let ContainerAttr {
+        crate_path,
+        tch_path,
+        dict,
+    } = &container;
+    let field_attrs = parse_all_field_attrs(fields)?;
+
+    let name = &input.ident;
+    let param_bounds = ParamBounds {
+        value: quote! { #crate_path::TryFromIValue },
+        hash_key: quote! {
+            #crate_path::TryFromIValue + ::core::cmp::Eq + ::core::hash::Hash
+        },
+        ord_key: quote! { #crate_path::TryFromIValue + ::core::cmp::Ord },
+        error: quote! { #crate_path::TryFromIValue },
+    };
+    let generics = add_trait_bounds(input.generics.clone(), fields, &field_attrs, &param_bounds);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let num_values = field_attrs
+        .iter()
+        .filter(|field_attr| !field_attr.skip)
+        .count();
+
+    let unpack = match (*dict, num_values) {
+        (false, 0) => quote! {
+            #crate_path::__private::unpack_tuple(value, 0)?;
+        },
+        (false, _) => quote! {
+            let mut values = #crate_path::__private::unpack_tuple(value, #num_values)?.into_iter();
+        },
+        (true, 0) => quote! {
+            #crate_path::__private::unpack_dict(value)?;
+        },
+        (true, _) => quote! {
+            let mut entries = #crate_path::__private::unpack_dict(value)?;
+        },
+    };
+
+    let field_values = fields
+        .iter()
+        .zip(&field_attrs)
+        .map(|(field, field_attr)| {
+            if field_attr.skip {
+                return Ok(quote! { ::core::default::Default::default() });
+            }
+
+            let value = if *dict {
+                let key = dict_key(field, field_attr)?;
+                quote! { #crate_path::__private::take_dict_entry(&mut entries, #key)? }
+            } else {
+                quote! { values.next().unwrap() }
+            };
+            Ok(quote! { #crate_path::TryFromIValue::try_from_ivalue(#value)? })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    let expanded = match fields {
+        Fields::Named(named_fields) => {
+            let field_names = named_fields.named.iter().map(|field| &field.ident);
+            quote! { Self { #(#field_names: #field_values),* } }
+        }
+        Fields::Unnamed(_) => quote! { Self(#(#field_values),*) },
+        Fields::Unit => quote! { Self },
+    };
+
+    Ok(quote! {
+        impl #impl_generics #crate_path::TryFromIValue for #name #ty_generics #where_clause {
+            fn try_from_ivalue(
+                value: #tch_path::IValue,
+            ) -> ::core::result::Result<Self, #tch_path::TchError> {
+                #unpack
+                ::core::result::Result::Ok(#expanded)
+            }
+        }
+    })
+}
+
+/// Returns the fields of a struct, as enums and unions have no IValue
+/// counterpart.
+fn struct_fields<'a>(input: &'a DeriveInput, trait_name: &str) -> Result<&'a Fields, Error> {
+    match &input.data {
+        Data::Struct(data) => Ok(&data.fields),
+        Data::Enum(data) => Err(Error::new(
+            data.enum_token.span(),
+            format!("{} cannot be derived for enums", trait_name),
+        )),
+        Data::Union(data) => Err(Error::new(
+            data.union_token.span(),
+            format!("{} cannot be derived for unions", trait_name),
+        )),
+    }
+}
+
+fn dict_key(field: &Field, field_attr: &FieldAttr) -> Result<String, Error> {
+    if let Some(rename) = &field_attr.rename {
+        return Ok(rename.clone());
+    }
+
+    match &field.ident {
+        Some(ident) => Ok(ident.to_string()),
+        None => Err(Error::new(
+            field.span(),
+            r#"fields of tuple structs require #[ivalue(rename = "...")] in dict mode"#,
+        )),
+    }
+}
+
+fn parse_container_attrs(attrs: &[Attribute]) -> Result<ContainerAttr, Error> {
+    let mut crate_path = None;
+    let mut tch_path = None;
+    let mut dict = false;
+
+    for (attr, nested) in parse_metas(attrs, "ivalue")? {
+        match &nested {
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("dict") => {
+                if dict {
+                    return Err(Error::new(path.span(), r#"duplicated attribute "dict""#));
+                }
+                dict = true;
+            }
+            NestedMeta::Meta(Meta::NameValue(name_value)) => {
+                let ident = name_value
+                    .path
+                    .get_ident()
+                    .ok_or_else(|| Error::new(attr.span(), "expected #[ivalue(...)]"))?;
+                let value = match &name_value.lit {
+                    Lit::Str(value) => value,
+                    lit => return Err(Error::new(lit.span(), "expected a string literal")),
+                };
+                let name = ident.to_string();
+                let slot = match name.as_str() {
+                    "crate" => &mut crate_path,
+                    "tch" => &mut tch_path,
+                    name => {
+                        return Err(Error::new(
+                            ident.span(),
+                            format!(r#"unexpected attribute name "{}""#, name),
+                        ))
+                    }
+                };
+
+                if slot.is_some() {
+                    return Err(Error::new(
+                        name_value.span(),
+                        format!(r#"duplicated attribute "{}""#, name),
+                    ));
+                }
+                let path: Path = value.parse()?;
+                *slot = Some(quote! { #path });
+            }
+            nested => return Err(Error::new(nested.span(), "unexpected attribute")),
+        }
+    }
+
+    Ok(ContainerAttr {
+        crate_path: crate_path.unwrap_or_else(|| quote! { ::tch_tensor_like }),
+        tch_path: tch_path.unwrap_or_else(|| quote! { ::tch }),
+        dict,
+    })
+}
+
+fn parse_all_field_attrs(fields: &Fields) -> Result<Vec<FieldAttr>, Error> {
+    fields.iter().map(parse_field_attrs).collect()
+}
+
+fn parse_field_attrs(field: &Field) -> Result<FieldAttr, Error> {
+    let mut skip = false;
+    let mut rename = None;
+
+    for (attr, nested) in parse_metas(&field.attrs, "ivalue")? {
+        match &nested {
+            NestedMeta::Meta(Meta::Path(path)) if path.is_ident("skip") => {
+                if skip {
+                    return Err(Error::new(path.span(), r#"duplicated attribute "skip""#));
+                }
+                skip = true;
+            }
+            NestedMeta::Meta(Meta::NameValue(name_value)) if name_value.path.is_ident("rename") => {
+                if rename.is_some() {
+                    return Err(Error::new(
+                        name_value.span(),
+                        r#"duplicated attribute "rename""#,
+                    ));
+                }
+
+                match &name_value.lit {
+                    Lit::Str(value) => rename = Some(value.value()),
+                    lit => return Err(Error::new(lit.span(), "expected a string literal")),
+                }
+            }
+            NestedMeta::Meta(meta) => {
+                let name = meta
+                    .path()
+                    .get_ident()
+                    .ok_or_else(|| Error::new(attr.span(), "expected #[ivalue(...)]"))?;
+                return Err(Error::new(
+                    name.span(),
+                    format!(r#"unexpected attribute name "{}""#, name),
+                ));
+            }
+            nested => return Err(Error::new(nested.span(), "unexpected attribute")),
+        }
+    }
+
+    Ok(FieldAttr {
+        skip: skip || is_phantom_data(&field.ty),
+        rename,
+    })
+}
+
+fn is_phantom_data(ty: &Type) -> bool {
+    match ty {
+        Type::Path(type_path) => type_path
+            .path
+            .segments
+            .last()
+            .map(|segment| segment.ident == "PhantomData")
+            .unwrap_or(false),
+        _ => false,
+    }
+}
+
+/// Bounds the type parameters of each converted field as described in
+/// [ParamBounds], and skipped field types mentioning type parameters by
+/// `Default`.
+fn add_trait_bounds(
+    mut generics: Generics,
+    fields: &Fields,
+    field_attrs: &[FieldAttr],
+    param_bounds: &ParamBounds,
+) -> Generics {
+    let type_params: HashSet<Ident> = generics
+        .type_params()
+        .map(|param| param.ident.clone())
+        .collect();
+    let mut predicates = vec![];
+
+    for (field, field_attr) in fields.iter().zip(field_attrs) {
+        let ty = &field.ty;
+
+        if !field_attr.skip {
+            param_bounds.collect(ty, &type_params, &mut predicates);
+        } else if !is_phantom_data(ty) && mentions_type_params(ty.to_token_stream(), &type_params) {
+            push_predicate(&mut predicates, parse_quote!(#ty: ::core::default::Default));
+        }
+    }
+
+    generics.make_where_clause().predicates.extend(predicates);
+    generics
+}
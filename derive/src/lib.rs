@@ -1,23 +1,79 @@
-use proc_macro2::TokenStream;
+use proc_macro2::{TokenStream, TokenTree};
 // use proc_macro::TokenStream;
-use quote::{format_ident, quote, quote_spanned};
+use quote::{format_ident, quote, quote_spanned, ToTokens};
+use std::collections::HashSet;
 use syn::{
-    parse_macro_input, parse_quote, spanned::Spanned, Attribute, Data, DataStruct, DeriveInput,
-    Error, Fields, GenericParam, Generics, Ident, Meta, NestedMeta,
+    ext::IdentExt, parse_macro_input, parse_quote, punctuated::Punctuated, spanned::Spanned,
+    Attribute, Data, DataStruct, DeriveInput, Error, Field, Fields, GenericArgument, Generics,
+    Ident, Lit, LitStr, Meta, NestedMeta, Path, PathArguments, Token, Type, WherePredicate,
 };
 
+mod ivalue;
+
+#[derive(Debug, Clone)]
+struct ContainerAttr {
+    /// The path to this crate, set by `#[tensor_like(crate = "...")]`.
+    pub crate_path: TokenStream,
+    /// The path to the tch crate, set by `#[tensor_like(tch = "...")]`.
+    pub tch_path: TokenStream,
+    /// Replaces the inferred where clause, set by `#[tensor_like(bound = "...")]`.
+    pub bound: Option<TokenStream>,
+}
+
 #[derive(Debug, Clone)]
 struct FieldAttr {
     pub clone_kind: CloneKind,
+    pub frozen: bool,
+    pub kind: KindAttr,
+    pub device: DeviceAttr,
+    pub with: Option<TokenStream>,
+    /// Replaces the bound inferred from the field type.
+    pub bound: Option<TokenStream>,
+}
+
+impl FieldAttr {
+    /// Returns the path to the function mapping the field, which is in the
+    /// `with` module if present or the `TensorLike` trait otherwise.
+    fn callee(&self, container: &ContainerAttr) -> TokenStream {
+        let crate_path = &container.crate_path;
+
+        match &self.with {
+            Some(with) => with.clone(),
+            None => quote! { #crate_path::TensorLike },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 enum CloneKind {
     Clone,
     Copy,
+    /// Fills the field with `Default::default()` or the given function.
+    Skip(Option<TokenStream>),
     None,
 }
 
+#[derive(Debug, Clone)]
+enum KindAttr {
+    /// Converts to the requested kind.
+    Inherit,
+    /// Never changes the kind, set by `#[tensor_like(kind = "keep")]`.
+    Keep,
+    /// Converts to a fixed kind, set by `#[tensor_like(kind = "Float")]`.
+    Fixed(Ident),
+}
+
+#[derive(Debug, Clone)]
+enum DeviceAttr {
+    /// Moves to the requested device.
+    Inherit,
+    /// Never moves the field, set by `#[tensor_like(no_device)]`.
+    Keep,
+    /// Moves to a fixed device, set by `#[tensor_like(device = "cpu")]`. The
+    /// path is relative to the tch crate.
+    Fixed(TokenStream),
+}
+
 #[proc_macro_derive(TensorLike, attributes(tensor_like))]
 pub fn derive_tensor_like(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
@@ -25,36 +81,79 @@ pub fn derive_tensor_like(input: proc_macro::TokenStream) -> proc_macro::TokenSt
     proc_macro::TokenStream::from(expanded)
 }
 
+#[proc_macro_derive(IntoIValue, attributes(ivalue))]
+pub fn derive_into_ivalue(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let expanded = ivalue::parse_into_ivalue(input).unwrap_or_else(|err| err.to_compile_error());
+    proc_macro::TokenStream::from(expanded)
+}
+
+#[proc_macro_derive(TryFromIValue, attributes(ivalue))]
+pub fn derive_try_from_ivalue(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let expanded =
+        ivalue::parse_try_from_ivalue(input).unwrap_or_else(|err| err.to_compile_error());
+    proc_macro::TokenStream::from(expanded)
+}
+
 fn parse_tensor_like(input: DeriveInput) -> Result<TokenStream, Error> {
+    if let Data::Union(data) = &input.data {
+        return Err(Error::new(
+            data.union_token.span(),
+            "TensorLike cannot be derived for unions",
+        ));
+    }
+
     let name = input.ident;
-    let generics = add_trait_bounds(input.generics);
+    let container = parse_container_attrs(&input.attrs)?;
+    let generics = add_trait_bounds(input.generics, &input.data, &container)?;
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
-    let f_to_device_impl = derive_f_to_device_impl(&input.data)?;
-    let f_to_kind_impl = derive_f_to_kind_impl(&input.data)?;
-    let shallow_clone_impl = derive_shallow_clone_impl(&input.data)?;
+    let f_map_tensors_impl = derive_f_map_tensors_impl(&input.data, &container, false)?;
+    let f_map_tensors_into_impl = derive_f_map_tensors_impl(&input.data, &container, true)?;
+    let field_assertions = derive_field_assertions(&input.data, &container)?;
+
+    let crate_path = &container.crate_path;
 
     let expanded = quote! {
-        impl #impl_generics tch_tensor_like::TensorLike for #name #ty_generics #where_clause {
-            fn f_to_device(&self, device: tch::Device) -> Result<Self, tch::TchError> {
-                #f_to_device_impl
+        impl #impl_generics #crate_path::TensorLike for #name #ty_generics #where_clause {
+            fn f_map_tensors(
+                &self,
+                mapper: &mut #crate_path::TensorMapper<'_>,
+            ) -> ::core::result::Result<Self, #crate_path::TensorLikeError> {
+                #f_map_tensors_impl
             }
 
-            fn f_to_kind(&self, kind: tch::Kind) -> Result<Self, tch::TchError> {
-                #f_to_kind_impl
+            fn f_map_tensors_into(
+                self,
+                mapper: &mut #crate_path::TensorMapper<'_>,
+            ) -> ::core::result::Result<Self, #crate_path::TensorLikeError> {
+                #f_map_tensors_into_impl
             }
+        }
 
-            fn shallow_clone(&self) -> Self {
-                #shallow_clone_impl
+        const _: () = {
+            #[allow(dead_code, clippy::all)]
+            fn assert_fields #impl_generics () #where_clause {
+                #field_assertions
             }
-        }
+        };
     };
 
     Ok(expanded)
 }
 
-fn derive_impl<F>(data: &Data, transform: F) -> Result<TokenStream, Error>
+/// Builds the value of each field with `transform`, which receives the
+/// `.map_err(...)` call attaching the path of the field to errors. If
+/// `by_value` is true, `self` is taken by value and cloned or copied fields
+/// are moved instead.
+fn derive_impl<F>(
+    data: &Data,
+    container: &ContainerAttr,
+    by_value: bool,
+    transform: F,
+) -> Result<TokenStream, Error>
 where
-    F: Fn(&Ident) -> TokenStream,
+    F: Fn(&Ident, &FieldAttr, &TokenStream) -> TokenStream,
 {
     let expanded = match data {
         Data::Struct(DataStruct {
@@ -63,7 +162,7 @@ where
         }) => {
             let extract_idents = named_fields.named.iter().enumerate().map(|(index, field)| {
                 let field_name = &field.ident;
-                let proxy_name = format_ident!("_{}", index);
+                let proxy_name = format_ident!("_{}", index, span = field.ty.span());
                 quote_spanned! {
                     field.span() =>
                         #field_name: #proxy_name
@@ -75,20 +174,26 @@ where
                 .iter()
                 .enumerate()
                 .map(|(index, field)| {
-                    let FieldAttr { clone_kind } = parse_field_attrs(&field.attrs)?;
+                    let field_attr = parse_field_attrs(&field.attrs)?;
                     let field_name = &field.ident;
-                    let proxy_name = format_ident!("_{}", index);
+                    let proxy_name = format_ident!("_{}", index, span = field.ty.span());
+                    let context = field_context(container, None, field, index);
 
-                    let expanded_value = match clone_kind {
+                    let expanded_value = match &field_attr.clone_kind {
+                        CloneKind::Clone | CloneKind::Copy if by_value => quote_spanned! {
+                            field.span() =>
+                                #proxy_name
+                        },
                         CloneKind::Clone => quote_spanned! {
                             field.span() =>
-                                Clone::clone(#proxy_name)
+                                ::core::clone::Clone::clone(#proxy_name)
                         },
                         CloneKind::Copy => quote_spanned! {
                             field.span() =>
                                 *#proxy_name
                         },
-                        CloneKind::None => transform(&proxy_name),
+                        CloneKind::Skip(default) => skipped_value(field, default),
+                        CloneKind::None => transform(&proxy_name, &field_attr, &context),
                     };
 
                     Ok(quote_spanned! {
@@ -119,7 +224,7 @@ where
                 .iter()
                 .enumerate()
                 .map(|(index, field)| {
-                    let proxy_name = format_ident!("_{}", index);
+                    let proxy_name = format_ident!("_{}", index, span = field.ty.span());
                     quote_spanned! {
                         field.span() =>
                             #proxy_name
@@ -132,18 +237,24 @@ where
                 .iter()
                 .enumerate()
                 .map(|(index, field)| {
-                    let FieldAttr { clone_kind } = parse_field_attrs(&field.attrs)?;
-                    let ident = format_ident!("_{}", index);
-                    let expanded_value = match clone_kind {
+                    let field_attr = parse_field_attrs(&field.attrs)?;
+                    let ident = format_ident!("_{}", index, span = field.ty.span());
+                    let context = field_context(container, None, field, index);
+                    let expanded_value = match &field_attr.clone_kind {
+                        CloneKind::Clone | CloneKind::Copy if by_value => quote_spanned! {
+                            field.span() =>
+                                #ident
+                        },
                         CloneKind::Clone => quote_spanned! {
                             field.span() =>
-                                Clone::clone(#ident)
+                                ::core::clone::Clone::clone(#ident)
                         },
                         CloneKind::Copy => quote_spanned! {
                             field.span() =>
                                 *#ident
                         },
-                        CloneKind::None => transform(&ident),
+                        CloneKind::Skip(default) => skipped_value(field, default),
+                        CloneKind::None => transform(&ident, &field_attr, &context),
                     };
 
                     Ok(quote_spanned! {
@@ -185,7 +296,8 @@ where
                             let expanded_fields =
                                 fields.named.iter().enumerate().map(|(index, field)| {
                                     let field_name = &field.ident;
-                                    let proxy_name = format_ident!("_{}", index);
+                                    let proxy_name =
+                                        format_ident!("_{}", index, span = field.ty.span());
 
                                     quote_spanned! {
                                         field.span() =>
@@ -198,19 +310,31 @@ where
                                 .iter()
                                 .enumerate()
                                 .map(|(index, field)| {
-                                    let FieldAttr { clone_kind } = parse_field_attrs(&field.attrs)?;
+                                    let field_attr = parse_field_attrs(&field.attrs)?;
                                     let field_name = &field.ident;
-                                    let proxy_name = format_ident!("_{}", index);
-                                    let expanded_value = match clone_kind {
+                                    let proxy_name =
+                                        format_ident!("_{}", index, span = field.ty.span());
+                                    let context =
+                                        field_context(container, Some(var_name), field, index);
+                                    let expanded_value = match &field_attr.clone_kind {
+                                        CloneKind::Clone | CloneKind::Copy if by_value => {
+                                            quote_spanned! {
+                                                field.span() =>
+                                                    #proxy_name
+                                            }
+                                        }
                                         CloneKind::Clone => quote_spanned! {
                                             field.span() =>
-                                                Clone::clone(#proxy_name)
+                                                ::core::clone::Clone::clone(#proxy_name)
                                         },
                                         CloneKind::Copy => quote_spanned! {
                                             field.span() =>
                                                 *#proxy_name
                                         },
-                                        CloneKind::None => transform(&proxy_name),
+                                        CloneKind::Skip(default) => skipped_value(field, default),
+                                        CloneKind::None => {
+                                            transform(&proxy_name, &field_attr, &context)
+                                        }
                                     };
 
                                     Ok(quote_spanned! {
@@ -231,7 +355,8 @@ where
                         Fields::Unnamed(fields) => {
                             let expanded_fields =
                                 fields.unnamed.iter().enumerate().map(|(index, field)| {
-                                    let proxy_name = format_ident!("_{}", index);
+                                    let proxy_name =
+                                        format_ident!("_{}", index, span = field.ty.span());
                                     quote_spanned! {
                                         field.span() =>
                                             #proxy_name
@@ -243,19 +368,31 @@ where
                                 .iter()
                                 .enumerate()
                                 .map(|(index, field)| {
-                                    let FieldAttr { clone_kind } = parse_field_attrs(&field.attrs)?;
-                                    let proxy_name = format_ident!("_{}", index);
+                                    let field_attr = parse_field_attrs(&field.attrs)?;
+                                    let proxy_name =
+                                        format_ident!("_{}", index, span = field.ty.span());
+                                    let context =
+                                        field_context(container, Some(var_name), field, index);
 
-                                    let expanded_value = match clone_kind {
+                                    let expanded_value = match &field_attr.clone_kind {
+                                        CloneKind::Clone | CloneKind::Copy if by_value => {
+                                            quote_spanned! {
+                                                field.span() =>
+                                                    #proxy_name
+                                            }
+                                        }
                                         CloneKind::Clone => quote_spanned! {
                                             field.span() =>
-                                                Clone::clone(#proxy_name)
+                                                ::core::clone::Clone::clone(#proxy_name)
                                         },
                                         CloneKind::Copy => quote_spanned! {
                                             field.span() =>
                                                 *#proxy_name
                                         },
-                                        CloneKind::None => transform(&proxy_name),
+                                        CloneKind::Skip(default) => skipped_value(field, default),
+                                        CloneKind::None => {
+                                            transform(&proxy_name, &field_attr, &context)
+                                        }
                                     };
 
                                     Ok(quote_spanned! {
@@ -290,121 +427,722 @@ where
                 }
             }
         }
-        Data::Union(_data) => quote! {
-            compile_error!("union type is not supported")
-        },
+        Data::Union(_) => unreachable!("unions are rejected in parse_tensor_like()"),
     };
 
     Ok(expanded)
 }
 
-fn parse_field_attrs(attrs: &[Attribute]) -> Result<FieldAttr, Error> {
-    let mut is_clone = false;
-    let mut is_copy = false;
+/// Collects the items in all `#[name(...)]` attributes.
+fn parse_metas<'a>(
+    attrs: &'a [Attribute],
+    name: &str,
+) -> Result<Vec<(&'a Attribute, NestedMeta)>, Error> {
+    let mut metas = vec![];
 
-    let metas_iter = attrs
-        .iter()
-        .filter(|attr| {
-            attr.path
-                .get_ident()
-                .map(|ident| ident == &format_ident!("tensor_like"))
-                .unwrap_or(false)
-        })
-        .map(|attr| (attr, attr.parse_meta()));
+    let attrs_iter = attrs.iter().filter(|attr| {
+        attr.path
+            .get_ident()
+            .map(|ident| ident == name)
+            .unwrap_or(false)
+    });
 
-    for (attr, result) in metas_iter {
-        let meta = match result {
-            Ok(meta) => meta,
-            Err(_) => return Err(Error::new(attr.span(), "expected #[tensor_like(...)]")),
+    for attr in attrs_iter {
+        let list = match attr.parse_meta() {
+            Ok(Meta::List(list)) => list,
+            _ => {
+                return Err(Error::new(
+                    attr.span(),
+                    format!("expected #[{}(...)]", name),
+                ))
+            }
         };
 
-        let list = match meta {
-            Meta::List(list) => list,
+        metas.extend(list.nested.into_iter().map(|nested| (attr, nested)));
+    }
+
+    Ok(metas)
+}
+
+fn parse_container_attrs(attrs: &[Attribute]) -> Result<ContainerAttr, Error> {
+    let mut crate_path = None;
+    let mut tch_path = None;
+    let mut bound = None;
+
+    for (attr, nested) in parse_metas(attrs, "tensor_like")? {
+        let name_value = match &nested {
+            NestedMeta::Meta(Meta::NameValue(name_value)) => name_value,
             _ => return Err(Error::new(attr.span(), "expected #[tensor_like(...)]")),
         };
+        let ident = name_value
+            .path
+            .get_ident()
+            .ok_or_else(|| Error::new(attr.span(), "expected #[tensor_like(...)]"))?;
+        let value = match &name_value.lit {
+            Lit::Str(value) => value,
+            lit => return Err(Error::new(lit.span(), "expected a string literal")),
+        };
 
-        for nested in list.nested.iter() {
-            let path = match nested {
-                NestedMeta::Meta(Meta::Path(path)) => path,
-                _ => return Err(Error::new(attr.span(), "expected #[tensor_like(...)]")),
-            };
+        match ident.to_string().as_str() {
+            "crate" => {
+                let path: Path = value.parse()?;
+                crate_path = Some(quote! { #path });
+            }
+            "tch" => {
+                let path: Path = value.parse()?;
+                tch_path = Some(quote! { #path });
+            }
+            "bound" => {
+                bound = Some(parse_bound(value)?);
+            }
+            name => {
+                return Err(Error::new(
+                    attr.span(),
+                    format!(r#"unexpected attribute name "{}""#, name),
+                ))
+            }
+        }
+    }
 
-            let ident = path
-                .get_ident()
-                .ok_or_else(|| Error::new(attr.span(), "expected #[tensor_like(...)]"))?;
+    Ok(ContainerAttr {
+        crate_path: crate_path.unwrap_or_else(|| quote! { ::tch_tensor_like }),
+        tch_path: tch_path.unwrap_or_else(|| quote! { ::tch }),
+        bound,
+    })
+}
 
-            match ident.to_string().as_str() {
-                "clone" => {
-                    is_clone = true;
-                }
-                "copy" => {
-                    is_copy = true;
+fn parse_field_attrs(attrs: &[Attribute]) -> Result<FieldAttr, Error> {
+    let mut clone = None;
+    let mut copy = None;
+    let mut frozen = None;
+    let mut skip = None;
+    let mut default = None;
+    let mut with = None;
+    let mut kind = None;
+    let mut device = None;
+    let mut bound = None;
+
+    for (attr, nested) in parse_metas(attrs, "tensor_like")? {
+        match &nested {
+            NestedMeta::Meta(Meta::Path(path)) => {
+                let ident = path
+                    .get_ident()
+                    .ok_or_else(|| Error::new(attr.span(), "expected #[tensor_like(...)]"))?;
+                let name = ident.to_string();
+                let flag = match name.as_str() {
+                    "clone" => &mut clone,
+                    "copy" => &mut copy,
+                    "frozen" => &mut frozen,
+                    "skip" => &mut skip,
+                    "no_device" => {
+                        if device.is_some() {
+                            return Err(Error::new(
+                                path.span(),
+                                r#"duplicated attribute "device""#,
+                            ));
+                        }
+
+                        device = Some((path.span(), DeviceAttr::Keep));
+                        continue;
+                    }
+                    name => {
+                        return Err(Error::new(
+                            ident.span(),
+                            format!(r#"unexpected attribute name "{}""#, name),
+                        ))
+                    }
+                };
+
+                if flag.is_some() {
+                    return Err(Error::new(
+                        path.span(),
+                        format!(r#"duplicated attribute "{}""#, name),
+                    ));
                 }
-                name @ _ => {
+                *flag = Some(path.span());
+            }
+            NestedMeta::Meta(Meta::NameValue(name_value)) => {
+                let ident = name_value
+                    .path
+                    .get_ident()
+                    .ok_or_else(|| Error::new(attr.span(), "expected #[tensor_like(...)]"))?;
+                let value = match &name_value.lit {
+                    Lit::Str(value) => value,
+                    lit => return Err(Error::new(lit.span(), "expected a string literal")),
+                };
+                let name = ident.to_string();
+
+                let is_duplicated = match name.as_str() {
+                    "kind" => kind.is_some(),
+                    "default" => default.is_some(),
+                    "with" => with.is_some(),
+                    "device" => device.is_some(),
+                    "bound" => bound.is_some(),
+                    _ => false,
+                };
+                if is_duplicated {
                     return Err(Error::new(
-                        attr.span(),
-                        format!(r#"unexpected attribute name "{}""#, name),
-                    ))
+                        name_value.span(),
+                        format!(r#"duplicated attribute "{}""#, name),
+                    ));
+                }
+
+                match name.as_str() {
+                    "kind" => {
+                        let kind_attr = match value.value().as_str() {
+                            "keep" => KindAttr::Keep,
+                            name => KindAttr::Fixed(value.parse().map_err(|_| {
+                                Error::new(value.span(), format!(r#"invalid kind name "{}""#, name))
+                            })?),
+                        };
+                        kind = Some((name_value.span(), kind_attr));
+                    }
+                    "default" => {
+                        let path: Path = value.parse()?;
+                        default = Some((name_value.span(), quote! { #path }));
+                    }
+                    "with" => {
+                        let path: Path = value.parse()?;
+                        with = Some((name_value.span(), quote! { #path }));
+                    }
+                    "device" => {
+                        device = Some((name_value.span(), DeviceAttr::Fixed(parse_device(value)?)));
+                    }
+                    "bound" => {
+                        bound = Some(parse_bound(value)?);
+                    }
+                    name => {
+                        return Err(Error::new(
+                            ident.span(),
+                            format!(r#"unexpected attribute name "{}""#, name),
+                        ))
+                    }
                 }
             }
+            _ => return Err(Error::new(attr.span(), "expected #[tensor_like(...)]")),
+        }
+    }
+
+    // clone, copy and skip are mutually exclusive, and the attributes
+    // controlling the conversion have no effect on such fields.
+    let not_converted = [
+        ("clone", clone),
+        ("copy", copy),
+        (
+            "skip",
+            skip.or_else(|| default.as_ref().map(|(span, _)| *span)),
+        ),
+    ];
+    let conversion_only = [
+        ("frozen", frozen),
+        ("kind", kind.as_ref().map(|(span, _)| *span)),
+        ("device", device.as_ref().map(|(span, _)| *span)),
+        ("with", with.as_ref().map(|(span, _)| *span)),
+    ];
+    let mut exclusive = not_converted
+        .iter()
+        .filter_map(|(name, span)| Some((name, (*span)?)));
+
+    if let Some((first, _)) = exclusive.next() {
+        let conflict = exclusive.next().or_else(|| {
+            conversion_only
+                .iter()
+                .find_map(|(name, span)| Some((name, (*span)?)))
+        });
+
+        if let Some((name, span)) = conflict {
+            return Err(Error::new(
+                span,
+                format!(r#"conflicting attributes "{}" and "{}""#, first, name),
+            ));
         }
     }
 
-    let clone_kind = if is_copy {
+    let clone_kind = if skip.is_some() || default.is_some() {
+        CloneKind::Skip(default.map(|(_, default)| default))
+    } else if copy.is_some() {
         CloneKind::Copy
-    } else if is_clone {
+    } else if clone.is_some() {
         CloneKind::Clone
     } else {
         CloneKind::None
     };
 
-    Ok(FieldAttr { clone_kind })
+    Ok(FieldAttr {
+        clone_kind,
+        frozen: frozen.is_some(),
+        kind: kind.map(|(_, kind)| kind).unwrap_or(KindAttr::Inherit),
+        device: device
+            .map(|(_, device)| device)
+            .unwrap_or(DeviceAttr::Inherit),
+        with: with.map(|(_, with)| with),
+        bound,
+    })
+}
+
+/// Parses comma-separated where predicates, such as `"T: TensorLike, U: Clone"`.
+fn parse_bound(value: &LitStr) -> Result<TokenStream, Error> {
+    let predicates = value.parse_with(Punctuated::<WherePredicate, Token![,]>::parse_terminated)?;
+    Ok(quote! { #predicates })
 }
 
-fn derive_f_to_device_impl(data: &Data) -> Result<TokenStream, Error> {
-    let expanded = derive_impl(data, |ident| {
-        quote_spanned! {
-            ident.span() =>
-                tch_tensor_like::TensorLike::f_to_device(#ident, device)?
+/// Generates the value of a field marked with `skip`.
+fn skipped_value(field: &Field, default: &Option<TokenStream>) -> TokenStream {
+    match default {
+        Some(default) => quote_spanned! {
+            field.span() =>
+                #default()
+        },
+        None => quote_spanned! {
+            field.span() =>
+                ::core::default::Default::default()
+        },
+    }
+}
+
+/// Parses device names in the form of "cpu", "cuda" or "cuda:N".
+fn parse_device(value: &LitStr) -> Result<TokenStream, Error> {
+    let name = value.value();
+    let span = value.span();
+
+    let expanded = match name.split_once(':') {
+        None if name == "cpu" => quote_spanned! { span => Device::Cpu },
+        None if name == "cuda" => quote_spanned! { span => Device::Cuda(0) },
+        Some(("cuda", index)) => {
+            let index: usize = index
+                .parse()
+                .map_err(|_| Error::new(span, format!(r#"invalid device name "{}""#, name)))?;
+            quote_spanned! { span => Device::Cuda(#index) }
+        }
+        _ => {
+            return Err(Error::new(
+                span,
+                format!(r#"invalid device name "{}""#, name),
+            ))
+        }
+    };
+
+    Ok(expanded)
+}
+
+/// Builds the body of `f_map_tensors()`, or of `f_map_tensors_into()` if
+/// `by_value` is true. Fields with a `with` module are passed to its
+/// `f_map_tensors()` by reference in both cases.
+fn derive_f_map_tensors_impl(
+    data: &Data,
+    container: &ContainerAttr,
+    by_value: bool,
+) -> Result<TokenStream, Error> {
+    let expanded = derive_impl(data, container, by_value, |ident, field_attr, context| {
+        let callee = field_attr.callee(container);
+        let call = match (&field_attr.with, by_value) {
+            (None, true) => quote_spanned! {
+                ident.span() =>
+                    #callee::f_map_tensors_into(#ident, mapper)
+            },
+            (Some(_), true) => quote_spanned! {
+                ident.span() =>
+                    #callee::f_map_tensors(&#ident, mapper)
+            },
+            (_, false) => quote_spanned! {
+                ident.span() =>
+                    #callee::f_map_tensors(#ident, mapper)
+            },
+        };
+        let arms = conversion_arms(field_attr, container);
+
+        if arms.is_empty() {
+            quote_spanned! {
+                ident.span() =>
+                    #call #context?
+            }
+        } else {
+            quote_spanned! {
+                ident.span() =>
+                    {
+                        let conversion = match mapper.conversion() {
+                            #(#arms,)*
+                            conversion => conversion,
+                        };
+                        mapper.with_conversion(conversion, |mapper| {
+                            #call
+                        })#context?
+                    }
+            }
         }
     })?;
 
     Ok(quote! {
-        Ok(#expanded)
+        ::core::result::Result::Ok(#expanded)
     })
 }
 
-fn derive_f_to_kind_impl(data: &Data) -> Result<TokenStream, Error> {
-    let expanded = derive_impl(data, |ident| {
-        quote_spanned! {
-            ident.span() =>
-                tch_tensor_like::TensorLike::f_to_kind(#ident, kind)?
+/// Builds the `.map_err(...)` call prepending the field, and the variant for
+/// enums, to the path of errors raised by the field.
+fn field_context(
+    container: &ContainerAttr,
+    variant: Option<&Ident>,
+    field: &Field,
+    index: usize,
+) -> TokenStream {
+    let crate_path = &container.crate_path;
+
+    let field_segment = match &field.ident {
+        Some(field_name) => {
+            let field_name = field_name.unraw().to_string();
+            quote! { #crate_path::PathSegment::Field(#field_name) }
         }
-    })?;
+        None => quote! { #crate_path::PathSegment::TupleField(#index) },
+    };
+    let variant_segment = variant.map(|variant| {
+        let variant = variant.unraw().to_string();
+        quote! { .at(#crate_path::PathSegment::Variant(#variant)) }
+    });
+
+    quote! {
+        .map_err(|err| {
+            #crate_path::TensorLikeError::from(err)
+                .at(#field_segment)
+                #variant_segment
+        })
+    }
+}
+
+/// Returns the match arms replacing the requested conversion for a field
+/// according to its attributes.
+fn conversion_arms(field_attr: &FieldAttr, container: &ContainerAttr) -> Vec<TokenStream> {
+    let ContainerAttr {
+        crate_path,
+        tch_path,
+        ..
+    } = container;
+    let mut arms = vec![];
+
+    if field_attr.frozen {
+        arms.push(quote! {
+            #crate_path::Conversion::RequiresGrad(_) => {
+                #crate_path::Conversion::RequiresGrad(false)
+            }
+        });
+    }
+
+    match &field_attr.kind {
+        KindAttr::Inherit => {}
+        KindAttr::Keep => arms.push(quote! {
+            #crate_path::Conversion::Kind(_) | #crate_path::Conversion::FloatKind(_) => {
+                #crate_path::Conversion::ShallowClone
+            }
+        }),
+        KindAttr::Fixed(fixed_kind) => {
+            arms.push(quote! {
+                #crate_path::Conversion::Kind(_) => {
+                    #crate_path::Conversion::Kind(#tch_path::Kind::#fixed_kind)
+                }
+            });
+            arms.push(quote! {
+                #crate_path::Conversion::FloatKind(_) => {
+                    #crate_path::Conversion::FloatKind(#tch_path::Kind::#fixed_kind)
+                }
+            });
+        }
+    }
+
+    match &field_attr.device {
+        DeviceAttr::Inherit => {}
+        DeviceAttr::Keep => arms.push(quote! {
+            #crate_path::Conversion::Device(_) => #crate_path::Conversion::ShallowClone
+        }),
+        DeviceAttr::Fixed(fixed_device) => arms.push(quote! {
+            #crate_path::Conversion::Device(_) => {
+                #crate_path::Conversion::Device(#tch_path::#fixed_device)
+            }
+        }),
+    }
+
+    arms
+}
+
+/// Builds the where clause of the impl.
+///
+/// The container `bound` attribute replaces all inferred bounds. Otherwise,
+/// each field is bounded according to how it is transformed, unless the field
+/// has its own `bound` attribute. Type parameters of transformed fields are
+/// bounded by their position in the field type as described in
+/// [ParamBounds], while copied, cloned and skipped fields mentioning type
+/// parameters require `Copy`, `Clone` and `Default` on the field type.
+fn add_trait_bounds(
+    mut generics: Generics,
+    data: &Data,
+    container: &ContainerAttr,
+) -> Result<Generics, Error> {
+    let crate_path = &container.crate_path;
+
+    let predicates: Punctuated<WherePredicate, Token![,]> = match &container.bound {
+        Some(bound) => parse_quote!(#bound),
+        None => {
+            let type_params: HashSet<Ident> = generics
+                .type_params()
+                .map(|param| param.ident.clone())
+                .collect();
+            let param_bounds = ParamBounds {
+                value: quote! { #crate_path::TensorLike },
+                hash_key: quote! {
+                    ::core::cmp::Eq
+                        + ::core::hash::Hash
+                        + ::core::clone::Clone
+                        + ::core::fmt::Debug
+                },
+                ord_key: quote! {
+                    ::core::cmp::Ord + ::core::clone::Clone + ::core::fmt::Debug
+                },
+                error: quote! { ::core::clone::Clone },
+            };
+            let mut predicates = vec![];
+
+            for field in data_fields(data) {
+                let field_attr = parse_field_attrs(&field.attrs)?;
+
+                if let Some(bound) = field_attr.bound {
+                    let bound: Punctuated<WherePredicate, Token![,]> = parse_quote!(#bound);
+                    predicates.extend(bound);
+                    continue;
+                }
+
+                let ty = &field.ty;
+                match (&field_attr.clone_kind, &field_attr.with) {
+                    (CloneKind::None, None) => {
+                        param_bounds.collect(ty, &type_params, &mut predicates);
+                    }
+                    _ => {
+                        if let Some(bound) = field_bound(&field_attr, container) {
+                            if mentions_type_params(ty.to_token_stream(), &type_params) {
+                                push_predicate(&mut predicates, parse_quote!(#ty: #bound));
+                            }
+                        }
+                    }
+                }
+            }
+
+            predicates.into_iter().collect()
+        }
+    };
+
+    generics.make_where_clause().predicates.extend(predicates);
+    Ok(generics)
+}
+
+/// Returns the trait required by the derived impl on a field type, if any.
+fn field_bound(field_attr: &FieldAttr, container: &ContainerAttr) -> Option<TokenStream> {
+    let crate_path = &container.crate_path;
+
+    let bound = match (&field_attr.clone_kind, &field_attr.with) {
+        (CloneKind::Clone, _) => quote! { ::core::clone::Clone },
+        (CloneKind::Copy, _) => quote! { ::core::marker::Copy },
+        (CloneKind::Skip(None), _) => quote! { ::core::default::Default },
+        (CloneKind::Skip(Some(_)), _) | (CloneKind::None, Some(_)) => return None,
+        (CloneKind::None, None) => quote! { #crate_path::TensorLike },
+    };
+    Some(bound)
+}
+
+/// Generates an assertion for each field, so that unsatisfied trait bounds
+/// and `with` modules lacking `f_map_tensors()` are reported at the field
+/// rather than at the derive.
+fn derive_field_assertions(data: &Data, container: &ContainerAttr) -> Result<TokenStream, Error> {
+    let crate_path = &container.crate_path;
+
+    let assertions = data_fields(data)
+        .into_iter()
+        .map(|field| {
+            let field_attr = parse_field_attrs(&field.attrs)?;
+            let ty = &field.ty;
+
+            let assertion = match (&field_attr.clone_kind, &field_attr.with) {
+                (CloneKind::None, Some(with)) => Some(quote_spanned! {
+                    ty.span() =>
+                        {
+                            fn assert_with<T, E>(
+                                _: fn(
+                                    &T,
+                                    &mut #crate_path::TensorMapper<'_>,
+                                ) -> ::core::result::Result<T, E>,
+                            ) where
+                                E: ::core::convert::Into<#crate_path::TensorLikeError>,
+                            {
+                            }
+                            assert_with::<#ty, _>(#with::f_map_tensors);
+                        }
+                }),
+                _ => field_bound(&field_attr, container).map(|bound| {
+                    quote_spanned! {
+                        ty.span() =>
+                            {
+                                fn assert_bound<T: #bound>() {}
+                                assert_bound::<#ty>();
+                            }
+                    }
+                }),
+            };
+            Ok(assertion)
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
 
     Ok(quote! {
-        Ok(#expanded)
+        #(#assertions)*
     })
 }
 
-fn derive_shallow_clone_impl(data: &Data) -> Result<TokenStream, Error> {
-    let expanded = derive_impl(data, |ident| {
-        quote_spanned! {
-            ident.span() =>
-                tch_tensor_like::TensorLike::shallow_clone(#ident)
+/// The traits required of the type parameters in a field type, by the
+/// position of the parameter. Parameters that hold no data, such as the ones
+/// in `PhantomData<T>`, `&T` and `*const T`, are not bounded.
+struct ParamBounds {
+    /// Required of parameters holding values, e.g. `T` in `Vec<T>`.
+    value: TokenStream,
+    /// Required of hash map keys, e.g. `K` in `HashMap<K, V>`.
+    hash_key: TokenStream,
+    /// Required of ordered map keys, e.g. `K` in `BTreeMap<K, V>`.
+    ord_key: TokenStream,
+    /// Required of error types, e.g. `E` in `Result<T, E>`.
+    error: TokenStream,
+}
+
+impl ParamBounds {
+    /// Adds the bounds on the type parameters in `ty` to `predicates`.
+    fn collect(
+        &self,
+        ty: &Type,
+        type_params: &HashSet<Ident>,
+        predicates: &mut Vec<WherePredicate>,
+    ) {
+        match ty {
+            Type::Path(type_path) if type_path.qself.is_none() => {
+                let path = &type_path.path;
+                let first = &path.segments[0].ident;
+
+                // a type parameter, or an associated type of it
+                if path.leading_colon.is_none() && type_params.contains(first) {
+                    let value = &self.value;
+                    push_predicate(predicates, parse_quote!(#ty: #value));
+                    return;
+                }
+
+                let last = match path.segments.last() {
+                    Some(last) => last,
+                    None => return,
+                };
+                let args: Vec<&Type> = match &last.arguments {
+                    PathArguments::AngleBracketed(args) => args
+                        .args
+                        .iter()
+                        .filter_map(|arg| match arg {
+                            GenericArgument::Type(ty) => Some(ty),
+                            _ => None,
+                        })
+                        .collect(),
+                    _ => vec![],
+                };
+
+                let key_bound = match last.ident.to_string().as_str() {
+                    "PhantomData" => return,
+                    "HashMap" | "IndexMap" => Some(&self.hash_key),
+                    "BTreeMap" => Some(&self.ord_key),
+                    "Result" if args.len() == 2 => {
+                        self.collect(args[0], type_params, predicates);
+                        self.bound_mentioned(args[1], type_params, &self.error, predicates);
+                        return;
+                    }
+                    _ => None,
+                };
+
+                match (key_bound, args.split_first()) {
+                    (Some(key_bound), Some((key, values))) => {
+                        self.bound_mentioned(key, type_params, key_bound, predicates);
+                        values
+                            .iter()
+                            .for_each(|ty| self.collect(ty, type_params, predicates));
+                    }
+                    _ => args
+                        .iter()
+                        .for_each(|ty| self.collect(ty, type_params, predicates)),
+                }
+            }
+            Type::Reference(_) | Type::Ptr(_) => {}
+            Type::Array(array) => self.collect(&array.elem, type_params, predicates),
+            Type::Slice(slice) => self.collect(&slice.elem, type_params, predicates),
+            Type::Paren(paren) => self.collect(&paren.elem, type_params, predicates),
+            Type::Group(group) => self.collect(&group.elem, type_params, predicates),
+            Type::Tuple(tuple) => tuple
+                .elems
+                .iter()
+                .for_each(|ty| self.collect(ty, type_params, predicates)),
+            ty => self.bound_mentioned(ty, type_params, &self.value, predicates),
         }
-    })?;
+    }
 
-    Ok(expanded)
+    /// Bounds every type parameter mentioned in `ty` by `bound`.
+    fn bound_mentioned(
+        &self,
+        ty: &Type,
+        type_params: &HashSet<Ident>,
+        bound: &TokenStream,
+        predicates: &mut Vec<WherePredicate>,
+    ) {
+        for param in mentioned_type_params(ty.to_token_stream(), type_params) {
+            push_predicate(predicates, parse_quote!(#param: #bound));
+        }
+    }
 }
 
-fn add_trait_bounds(mut generics: Generics) -> Generics {
-    for param in &mut generics.params {
-        if let GenericParam::Type(type_param) = param {
-            type_param
-                .bounds
-                .push(parse_quote!(tch_tensor_like::TensorLike));
+/// Adds `predicate` to `predicates` unless an identical one is present.
+fn push_predicate(predicates: &mut Vec<WherePredicate>, predicate: WherePredicate) {
+    let tokens = predicate.to_token_stream().to_string();
+    let is_present = predicates
+        .iter()
+        .any(|other| other.to_token_stream().to_string() == tokens);
+
+    if !is_present {
+        predicates.push(predicate);
+    }
+}
+
+/// Lists the fields of a struct or of all enum variants.
+fn data_fields(data: &Data) -> Vec<&Field> {
+    match data {
+        Data::Struct(data) => data.fields.iter().collect(),
+        Data::Enum(data) => data
+            .variants
+            .iter()
+            .flat_map(|variant| variant.fields.iter())
+            .collect(),
+        Data::Union(_) => vec![],
+    }
+}
+
+/// Lists the type parameters appearing in `tokens` in order of appearance.
+fn mentioned_type_params(tokens: TokenStream, type_params: &HashSet<Ident>) -> Vec<Ident> {
+    fn collect(tokens: TokenStream, type_params: &HashSet<Ident>, mentioned: &mut Vec<Ident>) {
+        for token in tokens {
+            match token {
+                TokenTree::Ident(ident)
+                    if type_params.contains(&ident) && !mentioned.contains(&ident) =>
+                {
+                    mentioned.push(ident)
+                }
+                TokenTree::Group(group) => collect(group.stream(), type_params, mentioned),
+                _ => {}
+            }
         }
     }
-    generics
+
+    let mut mentioned = vec![];
+    collect(tokens, type_params, &mut mentioned);
+    mentioned
+}
+
+fn mentions_type_params(tokens: TokenStream, type_params: &HashSet<Ident>) -> bool {
+    tokens.into_iter().any(|token| match token {
+        TokenTree::Ident(ident) => type_params.contains(&ident),
+        TokenTree::Group(group) => mentions_type_params(group.stream(), type_params),
+        _ => false,
+    })
 }
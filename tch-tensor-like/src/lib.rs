@@ -1,266 +1,646 @@
 #[cfg(feature = "derive")]
-pub use tch_tensor_like_derive::TensorLike;
+pub use tch_tensor_like_derive::{IntoIValue, TensorLike, TryFromIValue};
 
 use std::{
-    collections::{BTreeMap, HashMap, LinkedList, VecDeque},
+    any,
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, HashSet, LinkedList, VecDeque},
+    fmt::{self, Debug, Display},
     hash::Hash,
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
+    rc::{self, Rc},
+    sync::{self, Arc, Mutex, RwLock},
 };
-use tch::{Device, Kind, TchError, Tensor};
+use tch::{Device, IValue, Kind, TchError, Tensor};
 
+#[cfg(feature = "arrayvec")]
+use arrayvec::ArrayVec;
+#[cfg(feature = "either")]
+use either::Either;
+#[cfg(feature = "indexmap")]
+use indexmap::IndexMap;
+#[cfg(feature = "smallvec")]
+use smallvec::SmallVec;
+#[cfg(feature = "nn")]
+use tch::nn;
+
+/// Values holding tensors that can be moved and converted as a whole.
+///
+/// [f_map_tensors](TensorLike::f_map_tensors) is the only required method.
+/// It rebuilds the value with each tensor converted by a [TensorMapper],
+/// and the other methods are provided on top of it. The fallible methods
+/// return a [TensorLikeError] locating the tensor that failed.
 pub trait TensorLike
 where
     Self: Sized,
 {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError>;
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError>;
-    fn shallow_clone(&self) -> Self;
+    /// Returns a value of the same shape where each tensor is replaced by
+    /// the result of [TensorMapper::f_map]. Impls pass the mapper on to
+    /// every tensor and to the `f_map_tensors` of every field holding
+    /// tensors, and copy or clone the remaining data. Errors coming from a
+    /// field are annotated with [TensorLikeError::at].
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError>;
 
-    fn to_device(&self, device: Device) -> Self {
-        self.f_to_device(device).unwrap()
+    /// Consumes the value and converts it like
+    /// [f_map_tensors](TensorLike::f_map_tensors), moving map keys and other
+    /// non-tensor data instead of cloning them. Falls back to `f_map_tensors`
+    /// by default.
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(mapper)
     }
 
-    fn to_kind(&self, kind: Kind) -> Self {
-        self.f_to_kind(kind).unwrap()
+    fn f_to_device(&self, device: Device) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::Device(device)))
     }
-}
 
-// primitives
+    fn f_to_kind(&self, kind: Kind) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::Kind(kind)))
+    }
 
-macro_rules! impl_for_primitive {
-    ($name:ty) => {
-        impl TensorLike for $name {
-            fn f_to_device(&self, _device: Device) -> Result<Self, TchError> {
-                Ok(*self)
-            }
+    /// Moves the value to `device` like [f_to_device](TensorLike::f_to_device),
+    /// but consumes the value to avoid cloning its non-tensor data.
+    fn f_into_device(self, device: Device) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors_into(&mut TensorMapper::new(Conversion::Device(device)))
+    }
 
-            fn f_to_kind(&self, _kind: Kind) -> Result<Self, TchError> {
-                Ok(*self)
-            }
+    /// Converts the value to `kind` like [f_to_kind](TensorLike::f_to_kind),
+    /// but consumes the value like [f_into_device](TensorLike::f_into_device).
+    fn f_into_kind(self, kind: Kind) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors_into(&mut TensorMapper::new(Conversion::Kind(kind)))
+    }
+
+    fn shallow_clone(&self) -> Self {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::ShallowClone))
+            .unwrap()
+    }
+
+    /// Moves the value to `device` like [f_to_device](TensorLike::f_to_device),
+    /// but tensors sharing the same storage view are moved once and the
+    /// results share one tensor. The `aliases` map records the transferred
+    /// tensors, as well as `Rc` and `Arc` pointers, which every method maps
+    /// once within a single value, and can be reused across several values.
+    fn f_to_device_aliased(
+        &self,
+        device: Device,
+        aliases: &mut AliasMap,
+    ) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::aliased(
+            Conversion::Device(device),
+            aliases,
+        ))
+    }
+
+    /// Returns a copy of the value with every tensor detached from the
+    /// computation graph.
+    fn f_detach(&self) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::Detach))
+    }
+
+    /// Detaches every tensor from the computation graph in place. Like
+    /// [Tensor::detach_], shallow clones of the tensors are detached as well.
+    fn detach_(&mut self) {
+        f_visit_tensors(self, &mut |tensor| {
+            let _ = tensor.shallow_clone().f_detach_()?;
+            Ok(())
+        })
+        .unwrap()
+    }
+
+    /// Sets the gradient tracking flag of every tensor in the value. Like
+    /// [Tensor::set_requires_grad], the flag is updated on the tensors in
+    /// place and the returned value shares them. Tensors of non-floating
+    /// point kinds cannot track gradients and are left untouched when
+    /// enabling the flag.
+    fn f_set_requires_grad(&self, requires_grad: bool) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::RequiresGrad(
+            requires_grad,
+        )))
+    }
+
+    /// Returns true if any tensor in the value tracks gradients.
+    fn requires_grad_any(&self) -> bool {
+        let mut requires_grad = false;
+        f_visit_tensors(self, &mut |tensor| {
+            requires_grad |= tensor.requires_grad();
+            Ok(())
+        })
+        .unwrap();
+        requires_grad
+    }
+
+    /// Returns a value of the same shape holding the gradient of each
+    /// tensor. Like [Tensor::grad], tensors without a gradient map to
+    /// undefined tensors, which can be checked with [Tensor::defined].
+    fn f_grads(&self) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::Grad))
+    }
+
+    /// Zeroes the gradients attached to every tensor in the value.
+    fn zero_grad(&self) {
+        f_visit_tensors(self, &mut |tensor| {
+            Tensor::zero_grad(&mut tensor.shallow_clone());
+            Ok(())
+        })
+        .unwrap();
+    }
+
+    /// Returns a copy of the value where every tensor is copied to newly
+    /// allocated storage like [Tensor::copy], so the result never aliases
+    /// the source tensors.
+    fn f_deep_clone(&self) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::DeepClone))
+    }
+
+    /// Converts every tensor in the value to the memory `format`. Tensors
+    /// whose dimensions do not fit the format, e.g. non 4-D tensors for
+    /// [MemoryFormat::ChannelsLast], are left untouched.
+    fn f_to_memory_format(&self, format: MemoryFormat) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::MemoryFormat(format)))
+    }
+
+    fn f_contiguous(&self) -> Result<Self, TensorLikeError> {
+        self.f_to_memory_format(MemoryFormat::Contiguous)
+    }
+
+    /// Converts tensors of floating point kinds to `kind` like
+    /// [f_to_kind](TensorLike::f_to_kind), while integer, boolean and
+    /// complex tensors keep their kinds.
+    fn f_to_float_kind(&self, kind: Kind) -> Result<Self, TensorLikeError> {
+        self.f_map_tensors(&mut TensorMapper::new(Conversion::FloatKind(kind)))
+    }
+
+    /// Calls `f` on every tensor in the value.
+    fn f_for_each_tensor<F>(&self, f: &mut F) -> Result<(), TensorLikeError>
+    where
+        F: FnMut(&Tensor),
+    {
+        f_visit_tensors(self, &mut |tensor| {
+            f(tensor);
+            Ok(())
+        })
+    }
+
+    fn for_each_tensor<F>(&self, f: &mut F)
+    where
+        F: FnMut(&Tensor),
+    {
+        self.f_for_each_tensor(f).unwrap()
+    }
+
+    /// Clips the gradients of all tensors in the value in place, so that
+    /// their total norm does not exceed `max_norm`. The norm is computed
+    /// over the norms of individual gradients, as if they were concatenated
+    /// into a single vector. Pass [f64::INFINITY] as `norm_type` for the
+    /// infinity norm. Tensors reachable more than once, such as shallow
+    /// clones of one parameter, are counted once. Returns the total norm
+    /// before clipping.
+    fn f_clip_grad_norm(&self, max_norm: f64, norm_type: f64) -> Result<f64, TensorLikeError> {
+        let mut grads = vec![];
+        self.f_for_each_tensor(&mut |tensor| grads.push(tensor.f_grad()))?;
+        let grads: Vec<Tensor> = grads
+            .into_iter()
+            .filter(|grad| grad.as_ref().map(|grad| grad.defined()).unwrap_or(true))
+            .collect::<Result<_, _>>()?;
 
-            fn shallow_clone(&self) -> Self {
-                *self
+        // a tensor reachable more than once must be counted and scaled once
+        let mut visited = HashSet::new();
+        let grads: Vec<_> = grads
+            .into_iter()
+            .filter(|grad| match AliasKey::new(grad) {
+                Some(key) => visited.insert(key),
+                None => true,
+            })
+            .collect();
+
+        if grads.is_empty() {
+            return Ok(0.0);
+        }
+
+        let total_norm = if norm_type.is_infinite() {
+            grads.iter().try_fold(0f64, |max, grad| {
+                let norm = grad.f_abs()?.f_max()?.f_double_value(&[])?;
+                Ok::<_, TchError>(max.max(norm))
+            })?
+        } else {
+            let sum = grads.iter().try_fold(0f64, |sum, grad| {
+                let norm = grad
+                    .f_flatten(0, -1)?
+                    .f_norm_scalaropt_dim(norm_type, &[0], false)?
+                    .f_double_value(&[])?;
+                Ok::<_, TchError>(sum + norm.powf(norm_type))
+            })?;
+            sum.powf(norm_type.recip())
+        };
+
+        let clip_coef = max_norm / (total_norm + 1e-6);
+        if clip_coef < 1.0 {
+            for mut grad in grads {
+                let _ = grad.f_mul_scalar_(clip_coef)?;
             }
         }
-    };
-}
 
-impl_for_primitive!(bool);
-impl_for_primitive!(f32);
-impl_for_primitive!(f64);
-impl_for_primitive!(usize);
-impl_for_primitive!(u8);
-impl_for_primitive!(u16);
-impl_for_primitive!(u32);
-impl_for_primitive!(u64);
-impl_for_primitive!(u128);
-impl_for_primitive!(isize);
-impl_for_primitive!(i8);
-impl_for_primitive!(i16);
-impl_for_primitive!(i32);
-impl_for_primitive!(i64);
-impl_for_primitive!(i128);
+        Ok(total_norm)
+    }
 
-// reference
+    fn clip_grad_norm(&self, max_norm: f64, norm_type: f64) -> f64 {
+        self.f_clip_grad_norm(max_norm, norm_type).unwrap()
+    }
 
-impl<T> TensorLike for &T {
-    fn f_to_device(&self, _device: Device) -> Result<Self, TchError> {
-        Ok(*self)
+    fn to_device(&self, device: Device) -> Self {
+        self.f_to_device(device).unwrap()
     }
 
-    fn f_to_kind(&self, _kind: Kind) -> Result<Self, TchError> {
-        Ok(*self)
+    fn into_device(self, device: Device) -> Self {
+        self.f_into_device(device).unwrap()
     }
 
-    fn shallow_clone(&self) -> Self {
-        *self
+    fn to_device_aliased(&self, device: Device, aliases: &mut AliasMap) -> Self {
+        self.f_to_device_aliased(device, aliases).unwrap()
     }
-}
 
-// pointer
+    fn to_kind(&self, kind: Kind) -> Self {
+        self.f_to_kind(kind).unwrap()
+    }
 
-impl<T> TensorLike for *const T {
-    fn f_to_device(&self, _device: Device) -> Result<Self, TchError> {
-        Ok(*self)
+    fn into_kind(self, kind: Kind) -> Self {
+        self.f_into_kind(kind).unwrap()
     }
 
-    fn f_to_kind(&self, _kind: Kind) -> Result<Self, TchError> {
-        Ok(*self)
+    fn detach(&self) -> Self {
+        self.f_detach().unwrap()
     }
 
-    fn shallow_clone(&self) -> Self {
-        *self
+    fn set_requires_grad(&self, requires_grad: bool) -> Self {
+        self.f_set_requires_grad(requires_grad).unwrap()
     }
-}
 
-impl<T> TensorLike for *mut T {
-    fn f_to_device(&self, _device: Device) -> Result<Self, TchError> {
-        Ok(*self)
+    fn grads(&self) -> Self {
+        self.f_grads().unwrap()
     }
 
-    fn f_to_kind(&self, _kind: Kind) -> Result<Self, TchError> {
-        Ok(*self)
+    fn deep_clone(&self) -> Self {
+        self.f_deep_clone().unwrap()
     }
 
-    fn shallow_clone(&self) -> Self {
-        *self
+    fn to_float_kind(&self, kind: Kind) -> Self {
+        self.f_to_float_kind(kind).unwrap()
+    }
+
+    fn to_memory_format(&self, format: MemoryFormat) -> Self {
+        self.f_to_memory_format(format).unwrap()
+    }
+
+    fn contiguous(&self) -> Self {
+        self.f_contiguous().unwrap()
     }
 }
 
-// tuples
+// mapper
 
-impl<T1> TensorLike for (T1,)
-where
-    T1: TensorLike,
-{
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
-        Ok((self.0.f_to_device(device)?,))
+/// The conversion applied to each tensor by a [TensorMapper].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Conversion {
+    /// Moves tensors to the device.
+    Device(Device),
+    /// Casts tensors to the kind.
+    Kind(Kind),
+    /// Creates new tensors sharing the storage of the source tensors.
+    ShallowClone,
+    /// Detaches tensors from the computation graph.
+    Detach,
+    /// Sets the gradient tracking flag of tensors in place.
+    RequiresGrad(bool),
+    /// Replaces tensors with their gradients.
+    Grad,
+    /// Copies tensors to newly allocated storage.
+    DeepClone,
+    /// Lays out tensors in the memory format.
+    MemoryFormat(MemoryFormat),
+    /// Casts floating point tensors to the kind.
+    FloatKind(Kind),
+}
+
+impl Conversion {
+    fn f_apply(self, tensor: &Tensor) -> Result<Tensor, TchError> {
+        match self {
+            Self::Device(device) => tensor.f_to_device(device),
+            Self::Kind(kind) => tensor.f_to_kind(kind),
+            Self::ShallowClone => Ok(tensor.shallow_clone()),
+            Self::Detach => tensor.f_detach(),
+            Self::RequiresGrad(true) if !is_differentiable(tensor.f_kind()?) => {
+                Ok(tensor.shallow_clone())
+            }
+            Self::RequiresGrad(requires_grad) => tensor.f_set_requires_grad(requires_grad),
+            Self::Grad => tensor.f_grad(),
+            Self::DeepClone if !tensor.defined() => Ok(tensor.shallow_clone()),
+            Self::DeepClone => {
+                let mut output = tensor.f_zeros_like()?;
+                output.f_copy_(tensor)?;
+                Ok(output)
+            }
+            Self::MemoryFormat(format) => format.f_apply(tensor),
+            Self::FloatKind(kind) if is_floating_point(tensor.f_kind()?) => tensor.f_to_kind(kind),
+            Self::FloatKind(_) => Ok(tensor.shallow_clone()),
+        }
     }
+}
+
+/// Returns true if the kind is a real floating point kind.
+fn is_floating_point(kind: Kind) -> bool {
+    matches!(
+        kind,
+        Kind::Half | Kind::Float | Kind::Double | Kind::BFloat16
+    )
+}
+
+/// Returns true if tensors of the kind can track gradients.
+fn is_differentiable(kind: Kind) -> bool {
+    matches!(
+        kind,
+        Kind::Half
+            | Kind::Float
+            | Kind::Double
+            | Kind::BFloat16
+            | Kind::ComplexHalf
+            | Kind::ComplexFloat
+            | Kind::ComplexDouble
+    )
+}
+
+/// Converts the tensors of a value passed to
+/// [f_map_tensors](TensorLike::f_map_tensors).
+pub struct TensorMapper<'a> {
+    conversion: Conversion,
+    aliases: Option<&'a mut AliasMap>,
+    visitor: Option<&'a mut Visitor<'a>>,
+    /// Records `Rc` and `Arc` pointers mapped without an [AliasMap].
+    pointers: PointerMap,
+}
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        Ok((self.0.f_to_kind(kind)?,))
+impl<'a> TensorMapper<'a> {
+    fn new(conversion: Conversion) -> Self {
+        Self {
+            conversion,
+            aliases: None,
+            visitor: None,
+            pointers: PointerMap::default(),
+        }
     }
 
-    fn shallow_clone(&self) -> Self {
-        (self.0.shallow_clone(),)
+    fn aliased(conversion: Conversion, aliases: &'a mut AliasMap) -> Self {
+        Self {
+            conversion,
+            aliases: Some(aliases),
+            visitor: None,
+            pointers: PointerMap::default(),
+        }
     }
-}
 
-impl<T1, T2> TensorLike for (T1, T2)
-where
-    T1: TensorLike,
-    T2: TensorLike,
-{
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
-        Ok((self.0.f_to_device(device)?, self.1.f_to_device(device)?))
+    /// Creates a mapper that calls `visitor` on each source tensor before
+    /// shallow cloning it, which the in-place methods use to reach every
+    /// tensor of a value.
+    fn visitor(visitor: &'a mut Visitor<'a>) -> Self {
+        Self {
+            conversion: Conversion::ShallowClone,
+            aliases: None,
+            visitor: Some(visitor),
+            pointers: PointerMap::default(),
+        }
     }
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        Ok((self.0.f_to_kind(kind)?, self.1.f_to_kind(kind)?))
+    /// Returns the conversion applied to tensors, for impls holding data
+    /// that depends on it.
+    pub fn conversion(&self) -> Conversion {
+        self.conversion
     }
 
-    fn shallow_clone(&self) -> Self {
-        (self.0.shallow_clone(), self.1.shallow_clone())
+    /// Calls `f` with the conversion replaced by `conversion`, for impls
+    /// converting some of their fields differently.
+    pub fn with_conversion<R, F>(&mut self, conversion: Conversion, f: F) -> R
+    where
+        F: FnOnce(&mut Self) -> R,
+    {
+        let outer = mem::replace(&mut self.conversion, conversion);
+        let output = f(self);
+        self.conversion = outer;
+        output
     }
-}
 
-impl<T1, T2, T3> TensorLike for (T1, T2, T3)
-where
-    T1: TensorLike,
-    T2: TensorLike,
-    T3: TensorLike,
-{
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
-        Ok((
-            self.0.f_to_device(device)?,
-            self.1.f_to_device(device)?,
-            self.2.f_to_device(device)?,
-        ))
+    /// Converts a tensor. When transferring with an [AliasMap], tensors
+    /// recorded in the map return the recorded result instead.
+    pub fn f_map(&mut self, tensor: &Tensor) -> Result<Tensor, TchError> {
+        if let Some(visitor) = &mut self.visitor {
+            visitor(tensor)?;
+        }
+
+        let conversion = self.conversion;
+
+        match &mut self.aliases {
+            Some(aliases) => aliases.f_get_or_insert_with(tensor, conversion),
+            None => conversion.f_apply(tensor),
+        }
     }
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        Ok((
-            self.0.f_to_kind(kind)?,
-            self.1.f_to_kind(kind)?,
-            self.2.f_to_kind(kind)?,
-        ))
+    /// Maps a shared pointer once per conversion and returns the recorded
+    /// result for other pointers to the same allocation.
+    fn f_map_shared<P, F>(&mut self, source: &P, f: F) -> Result<P, TensorLikeError>
+    where
+        P: SharedPointer,
+        F: FnOnce(&mut Self) -> Result<P, TensorLikeError>,
+    {
+        let conversion = self.conversion;
+
+        if let Some(output) = self.pointers().get(source, conversion) {
+            return Ok(output);
+        }
+
+        let output = f(self)?;
+        self.pointers().insert(source, conversion, &output);
+        Ok(output)
     }
 
-    fn shallow_clone(&self) -> Self {
-        (
-            self.0.shallow_clone(),
-            self.1.shallow_clone(),
-            self.2.shallow_clone(),
-        )
+    fn pointers(&mut self) -> &mut PointerMap {
+        match &mut self.aliases {
+            Some(aliases) => &mut aliases.pointers,
+            None => &mut self.pointers,
+        }
+    }
+}
+
+impl fmt::Debug for TensorMapper<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TensorMapper")
+            .field("conversion", &self.conversion)
+            .field("aliases", &self.aliases)
+            .finish_non_exhaustive()
     }
 }
 
-impl<T1, T2, T3, T4> TensorLike for (T1, T2, T3, T4)
+type Visitor<'a> = dyn FnMut(&Tensor) -> Result<(), TchError> + 'a;
+
+/// Calls `visitor` on every tensor in the value.
+fn f_visit_tensors<T>(value: &T, visitor: &mut Visitor<'_>) -> Result<(), TensorLikeError>
 where
-    T1: TensorLike,
-    T2: TensorLike,
-    T3: TensorLike,
-    T4: TensorLike,
+    T: TensorLike,
 {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
-        Ok((
-            self.0.f_to_device(device)?,
-            self.1.f_to_device(device)?,
-            self.2.f_to_device(device)?,
-            self.3.f_to_device(device)?,
-        ))
+    value.f_map_tensors(&mut TensorMapper::visitor(visitor))?;
+    Ok(())
+}
+
+// primitives
+
+macro_rules! impl_for_primitive {
+    ($name:ty) => {
+        impl TensorLike for $name {
+            fn f_map_tensors(
+                &self,
+                _mapper: &mut TensorMapper<'_>,
+            ) -> Result<Self, TensorLikeError> {
+                Ok(*self)
+            }
+        }
+    };
+}
+
+impl_for_primitive!(bool);
+impl_for_primitive!(f32);
+impl_for_primitive!(f64);
+impl_for_primitive!(usize);
+impl_for_primitive!(u8);
+impl_for_primitive!(u16);
+impl_for_primitive!(u32);
+impl_for_primitive!(u64);
+impl_for_primitive!(u128);
+impl_for_primitive!(isize);
+impl_for_primitive!(i8);
+impl_for_primitive!(i16);
+impl_for_primitive!(i32);
+impl_for_primitive!(i64);
+impl_for_primitive!(i128);
+impl_for_primitive!(char);
+impl_for_primitive!(());
+
+// string
+
+impl TensorLike for String {
+    fn f_map_tensors(&self, _mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(self.clone())
     }
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        Ok((
-            self.0.f_to_kind(kind)?,
-            self.1.f_to_kind(kind)?,
-            self.2.f_to_kind(kind)?,
-            self.3.f_to_kind(kind)?,
-        ))
+    fn f_map_tensors_into(self, _mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(self)
     }
+}
 
-    fn shallow_clone(&self) -> Self {
-        (
-            self.0.shallow_clone(),
-            self.1.shallow_clone(),
-            self.2.shallow_clone(),
-            self.3.shallow_clone(),
-        )
+// reference
+
+impl<T> TensorLike for &T {
+    fn f_map_tensors(&self, _mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(*self)
     }
 }
 
-impl<T1, T2, T3, T4, T5> TensorLike for (T1, T2, T3, T4, T5)
-where
-    T1: TensorLike,
-    T2: TensorLike,
-    T3: TensorLike,
-    T4: TensorLike,
-    T5: TensorLike,
-{
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
-        Ok((
-            self.0.f_to_device(device)?,
-            self.1.f_to_device(device)?,
-            self.2.f_to_device(device)?,
-            self.3.f_to_device(device)?,
-            self.4.f_to_device(device)?,
-        ))
+// pointer
+
+impl<T> TensorLike for *const T {
+    fn f_map_tensors(&self, _mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(*self)
     }
+}
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        Ok((
-            self.0.f_to_kind(kind)?,
-            self.1.f_to_kind(kind)?,
-            self.2.f_to_kind(kind)?,
-            self.3.f_to_kind(kind)?,
-            self.4.f_to_kind(kind)?,
-        ))
+impl<T> TensorLike for *mut T {
+    fn f_map_tensors(&self, _mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(*self)
     }
+}
 
-    fn shallow_clone(&self) -> Self {
-        (
-            self.0.shallow_clone(),
-            self.1.shallow_clone(),
-            self.2.shallow_clone(),
-            self.3.shallow_clone(),
-            self.4.shallow_clone(),
-        )
+// phantom
+
+impl<T> TensorLike for PhantomData<T>
+where
+    T: ?Sized,
+{
+    fn f_map_tensors(&self, _mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(PhantomData)
     }
 }
 
+// tuples
+
+macro_rules! impl_for_tuple {
+    ($($name:ident: $index:tt),+) => {
+        impl<$($name),+> TensorLike for ($($name,)+)
+        where
+            $($name: TensorLike,)+
+        {
+            fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+                Ok(($(
+                    self.$index
+                        .f_map_tensors(mapper)
+                        .at(PathSegment::TupleField($index))?,
+                )+))
+            }
+
+            fn f_map_tensors_into(
+                self,
+                mapper: &mut TensorMapper<'_>,
+            ) -> Result<Self, TensorLikeError> {
+                Ok(($(
+                    self.$index
+                        .f_map_tensors_into(mapper)
+                        .at(PathSegment::TupleField($index))?,
+                )+))
+            }
+        }
+    };
+}
+
+impl_for_tuple!(T1: 0);
+impl_for_tuple!(T1: 0, T2: 1);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3, T5: 4);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3, T5: 4, T6: 5);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3, T5: 4, T6: 5, T7: 6);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3, T5: 4, T6: 5, T7: 6, T8: 7);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3, T5: 4, T6: 5, T7: 6, T8: 7, T9: 8);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3, T5: 4, T6: 5, T7: 6, T8: 7, T9: 8, T10: 9);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3, T5: 4, T6: 5, T7: 6, T8: 7, T9: 8, T10: 9, T11: 10);
+impl_for_tuple!(T1: 0, T2: 1, T3: 2, T4: 3, T5: 4, T6: 5, T7: 6, T8: 7, T9: 8, T10: 9, T11: 10, T12: 11);
+
 // tensor
 
 impl TensorLike for Tensor {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
-        self.f_to_device(device)
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(mapper.f_map(self)?)
     }
+}
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        self.f_to_kind(kind)
+// device and kind
+
+/// Stored devices follow [to_device](TensorLike::to_device), so that they
+/// keep describing the tensors next to them.
+impl TensorLike for Device {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(match mapper.conversion() {
+            Conversion::Device(device) => device,
+            _ => *self,
+        })
     }
+}
 
-    fn shallow_clone(&self) -> Self {
-        self.shallow_clone()
+/// Stored kinds follow [to_kind](TensorLike::to_kind), and floating point
+/// kinds follow [to_float_kind](TensorLike::to_float_kind) as well.
+impl TensorLike for Kind {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(match mapper.conversion() {
+            Conversion::Kind(kind) => kind,
+            Conversion::FloatKind(kind) if is_floating_point(*self) => kind,
+            _ => *self,
+        })
     }
 }
 
@@ -270,18 +650,22 @@ impl<T> TensorLike for Vec<T>
 where
     T: TensorLike,
 {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
         self.iter()
-            .map(|tensor| tensor.f_to_device(device))
+            .enumerate()
+            .map(|(index, value)| value.f_map_tensors(mapper).at(PathSegment::Index(index)))
             .collect()
     }
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        self.iter().map(|tensor| tensor.f_to_kind(kind)).collect()
-    }
-
-    fn shallow_clone(&self) -> Self {
-        self.iter().map(|tensor| tensor.shallow_clone()).collect()
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                value
+                    .f_map_tensors_into(mapper)
+                    .at(PathSegment::Index(index))
+            })
+            .collect()
     }
 }
 
@@ -289,18 +673,22 @@ impl<T> TensorLike for LinkedList<T>
 where
     T: TensorLike,
 {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
         self.iter()
-            .map(|tensor| tensor.f_to_device(device))
+            .enumerate()
+            .map(|(index, value)| value.f_map_tensors(mapper).at(PathSegment::Index(index)))
             .collect()
     }
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        self.iter().map(|tensor| tensor.f_to_kind(kind)).collect()
-    }
-
-    fn shallow_clone(&self) -> Self {
-        self.iter().map(|tensor| tensor.shallow_clone()).collect()
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                value
+                    .f_map_tensors_into(mapper)
+                    .at(PathSegment::Index(index))
+            })
+            .collect()
     }
 }
 
@@ -308,88 +696,1573 @@ impl<T> TensorLike for VecDeque<T>
 where
     T: TensorLike,
 {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
         self.iter()
-            .map(|tensor| tensor.f_to_device(device))
+            .enumerate()
+            .map(|(index, value)| value.f_map_tensors(mapper).at(PathSegment::Index(index)))
             .collect()
     }
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        self.iter().map(|tensor| tensor.f_to_kind(kind)).collect()
-    }
-
-    fn shallow_clone(&self) -> Self {
-        self.iter().map(|tensor| tensor.shallow_clone()).collect()
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                value
+                    .f_map_tensors_into(mapper)
+                    .at(PathSegment::Index(index))
+            })
+            .collect()
     }
 }
 
 impl<K, T> TensorLike for HashMap<K, T>
 where
-    K: Eq + Hash + Clone,
+    K: Eq + Hash + Clone + Debug,
     T: TensorLike,
 {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
         self.iter()
-            .map(|(key, tensor)| Ok((key.clone(), tensor.f_to_device(device)?)))
+            .map(|(key, value)| {
+                let value = value
+                    .f_map_tensors(mapper)
+                    .map_err(|err| err.at(key_segment(key)))?;
+                Ok((key.clone(), value))
+            })
             .collect()
     }
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        self.iter()
-            .map(|(key, tensor)| Ok((key.clone(), tensor.f_to_kind(kind)?)))
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.into_iter()
+            .map(|(key, value)| {
+                let value = value
+                    .f_map_tensors_into(mapper)
+                    .map_err(|err| err.at(key_segment(&key)))?;
+                Ok((key, value))
+            })
             .collect()
     }
+}
 
-    fn shallow_clone(&self) -> Self {
+impl<K, T> TensorLike for BTreeMap<K, T>
+where
+    K: Ord + Clone + Debug,
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
         self.iter()
-            .map(|(key, tensor)| (key.clone(), tensor.shallow_clone()))
+            .map(|(key, value)| {
+                let value = value
+                    .f_map_tensors(mapper)
+                    .map_err(|err| err.at(key_segment(key)))?;
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.into_iter()
+            .map(|(key, value)| {
+                let value = value
+                    .f_map_tensors_into(mapper)
+                    .map_err(|err| err.at(key_segment(&key)))?;
+                Ok((key, value))
+            })
             .collect()
     }
 }
 
-impl<K, T> TensorLike for BTreeMap<K, T>
+// array
+
+impl<T, const N: usize> TensorLike for [T; N]
 where
-    K: Ord + Clone,
     T: TensorLike,
 {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
-        self.iter()
-            .map(|(key, tensor)| Ok((key.clone(), tensor.f_to_device(device)?)))
-            .collect()
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        try_collect_array(
+            self.iter()
+                .enumerate()
+                .map(|(index, value)| value.f_map_tensors(mapper).at(PathSegment::Index(index))),
+        )
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        try_collect_array(self.into_iter().enumerate().map(|(index, value)| {
+            value
+                .f_map_tensors_into(mapper)
+                .at(PathSegment::Index(index))
+        }))
+    }
+}
+
+/// Collects exactly `N` fallible items into an array.
+fn try_collect_array<T, E, I, const N: usize>(iter: I) -> Result<[T; N], E>
+where
+    I: Iterator<Item = Result<T, E>>,
+{
+    let vec: Vec<T> = iter.collect::<Result<_, _>>()?;
+    match vec.try_into() {
+        Ok(array) => Ok(array),
+        Err(_) => unreachable!("the iterator must yield exactly N items"),
     }
+}
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
+// smallvec
+
+#[cfg(feature = "smallvec")]
+impl<A> TensorLike for SmallVec<A>
+where
+    A: smallvec::Array,
+    A::Item: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
         self.iter()
-            .map(|(key, tensor)| Ok((key.clone(), tensor.f_to_kind(kind)?)))
+            .enumerate()
+            .map(|(index, value)| value.f_map_tensors(mapper).at(PathSegment::Index(index)))
             .collect()
     }
 
-    fn shallow_clone(&self) -> Self {
-        self.iter()
-            .map(|(key, tensor)| (key.clone(), tensor.shallow_clone()))
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                value
+                    .f_map_tensors_into(mapper)
+                    .at(PathSegment::Index(index))
+            })
             .collect()
     }
 }
 
-// option
+// arrayvec
 
-impl<T> TensorLike for Option<T>
+#[cfg(feature = "arrayvec")]
+impl<T, const CAP: usize> TensorLike for ArrayVec<T, CAP>
 where
     T: TensorLike,
 {
-    fn f_to_device(&self, device: Device) -> Result<Self, TchError> {
-        self.as_ref()
-            .map(|tensor| tensor.f_to_device(device))
-            .transpose()
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.iter()
+            .enumerate()
+            .map(|(index, value)| value.f_map_tensors(mapper).at(PathSegment::Index(index)))
+            .collect()
     }
 
-    fn f_to_kind(&self, kind: Kind) -> Result<Self, TchError> {
-        self.as_ref()
-            .map(|tensor| tensor.f_to_kind(kind))
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.into_iter()
+            .enumerate()
+            .map(|(index, value)| {
+                value
+                    .f_map_tensors_into(mapper)
+                    .at(PathSegment::Index(index))
+            })
+            .collect()
+    }
+}
+
+// indexmap
+
+/// Keeps the order of the entries.
+#[cfg(feature = "indexmap")]
+impl<K, T> TensorLike for IndexMap<K, T>
+where
+    K: Eq + Hash + Clone + Debug,
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.iter()
+            .map(|(key, value)| {
+                let value = value
+                    .f_map_tensors(mapper)
+                    .map_err(|err| err.at(key_segment(key)))?;
+                Ok((key.clone(), value))
+            })
+            .collect()
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.into_iter()
+            .map(|(key, value)| {
+                let value = value
+                    .f_map_tensors_into(mapper)
+                    .map_err(|err| err.at(key_segment(&key)))?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+}
+
+// either
+
+#[cfg(feature = "either")]
+impl<L, R> TensorLike for Either<L, R>
+where
+    L: TensorLike,
+    R: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(match self {
+            Either::Left(value) => Either::Left(
+                value
+                    .f_map_tensors(mapper)
+                    .at(PathSegment::Variant("Left"))?,
+            ),
+            Either::Right(value) => Either::Right(
+                value
+                    .f_map_tensors(mapper)
+                    .at(PathSegment::Variant("Right"))?,
+            ),
+        })
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(match self {
+            Either::Left(value) => Either::Left(
+                value
+                    .f_map_tensors_into(mapper)
+                    .at(PathSegment::Variant("Left"))?,
+            ),
+            Either::Right(value) => Either::Right(
+                value
+                    .f_map_tensors_into(mapper)
+                    .at(PathSegment::Variant("Right"))?,
+            ),
+        })
+    }
+}
+
+// option
+
+impl<T> TensorLike for Option<T>
+where
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.as_ref()
+            .map(|value| value.f_map_tensors(mapper).at(PathSegment::Variant("Some")))
             .transpose()
     }
 
-    fn shallow_clone(&self) -> Self {
-        self.as_ref().map(|tensor| tensor.shallow_clone())
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        self.map(|value| {
+            value
+                .f_map_tensors_into(mapper)
+                .at(PathSegment::Variant("Some"))
+        })
+        .transpose()
+    }
+}
+
+// result
+
+/// Maps the value and clones the error, which is moved by
+/// [f_map_tensors_into](TensorLike::f_map_tensors_into) instead.
+impl<T, E> TensorLike for Result<T, E>
+where
+    T: TensorLike,
+    E: Clone,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(match self {
+            Ok(value) => Ok(value.f_map_tensors(mapper).at(PathSegment::Variant("Ok"))?),
+            Err(err) => Err(err.clone()),
+        })
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(match self {
+            Ok(value) => Ok(value
+                .f_map_tensors_into(mapper)
+                .at(PathSegment::Variant("Ok"))?),
+            Err(err) => Err(err),
+        })
+    }
+}
+
+// cow
+
+/// Borrowed values are cloned first, so the result is always owned.
+impl<B> TensorLike for Cow<'_, B>
+where
+    B: ToOwned + ?Sized,
+    B::Owned: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        let owned = match self {
+            Cow::Borrowed(borrowed) => (*borrowed).to_owned().f_map_tensors(mapper),
+            Cow::Owned(owned) => owned.f_map_tensors(mapper),
+        };
+        Ok(Cow::Owned(owned.at(PathSegment::Deref("Cow"))?))
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        let owned = self
+            .into_owned()
+            .f_map_tensors_into(mapper)
+            .at(PathSegment::Deref("Cow"))?;
+        Ok(Cow::Owned(owned))
+    }
+}
+
+// nn
+
+// Other layers such as nn::Conv2D keep their configuration in private
+// fields, so they cannot be rebuilt here. They are converted through their
+// VarStore or a `with` module instead.
+
+#[cfg(feature = "nn")]
+impl TensorLike for nn::Linear {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(nn::Linear {
+            ws: mapper.f_map(&self.ws).at(PathSegment::Field("ws"))?,
+            bs: self.bs.f_map_tensors(mapper).at(PathSegment::Field("bs"))?,
+        })
+    }
+}
+
+#[cfg(feature = "nn")]
+impl TensorLike for nn::LSTMState {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(nn::LSTMState(
+            self.0
+                .f_map_tensors(mapper)
+                .at(PathSegment::TupleField(0))?,
+        ))
+    }
+}
+
+#[cfg(feature = "nn")]
+impl TensorLike for nn::GRUState {
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(nn::GRUState(
+            mapper.f_map(&self.0).at(PathSegment::TupleField(0))?,
+        ))
+    }
+}
+
+#[cfg(feature = "nn")]
+impl TensorLike for nn::Id {
+    fn f_map_tensors(&self, _mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(nn::Id())
+    }
+}
+
+// smart pointers
+
+impl<T> TensorLike for Box<T>
+where
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(Box::new(
+            (**self)
+                .f_map_tensors(mapper)
+                .at(PathSegment::Deref("Box"))?,
+        ))
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        let value = (*self)
+            .f_map_tensors_into(mapper)
+            .at(PathSegment::Deref("Box"))?;
+        Ok(Box::new(value))
+    }
+}
+
+/// Pointers to the same allocation within one value, or within one
+/// [AliasMap], map to one shared result. In-place methods such as
+/// [detach_](TensorLike::detach_) update the shared value without replacing
+/// the pointers, and [for_each_tensor](TensorLike::for_each_tensor) visits it
+/// once.
+impl<T> TensorLike for Rc<T>
+where
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        mapper.f_map_shared(self, |mapper| {
+            let value = (**self)
+                .f_map_tensors(mapper)
+                .at(PathSegment::Deref("Rc"))?;
+            Ok(Rc::new(value))
+        })
+    }
+}
+
+/// Pointers to the same allocation within one value, or within one
+/// [AliasMap], map to one shared result, like [Rc].
+impl<T> TensorLike for Arc<T>
+where
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        mapper.f_map_shared(self, |mapper| {
+            let value = (**self)
+                .f_map_tensors(mapper)
+                .at(PathSegment::Deref("Arc"))?;
+            Ok(Arc::new(value))
+        })
+    }
+}
+
+// interior mutability
+
+impl<T> TensorLike for Cell<T>
+where
+    T: TensorLike + Copy,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(Cell::new(
+            self.get()
+                .f_map_tensors(mapper)
+                .at(PathSegment::Deref("Cell"))?,
+        ))
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(Cell::new(
+            self.into_inner()
+                .f_map_tensors_into(mapper)
+                .at(PathSegment::Deref("Cell"))?,
+        ))
+    }
+}
+
+/// Returns an error if the value is mutably borrowed.
+impl<T> TensorLike for RefCell<T>
+where
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        let value = self
+            .try_borrow()
+            .map_err(|err| TchError::Torch(format!("cannot borrow RefCell: {}", err)))?;
+        Ok(RefCell::new(
+            value
+                .f_map_tensors(mapper)
+                .at(PathSegment::Deref("RefCell"))?,
+        ))
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        Ok(RefCell::new(
+            self.into_inner()
+                .f_map_tensors_into(mapper)
+                .at(PathSegment::Deref("RefCell"))?,
+        ))
+    }
+}
+
+/// Blocks until the lock is acquired, and returns an error if the lock is
+/// poisoned.
+impl<T> TensorLike for Mutex<T>
+where
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        let value = self.lock().map_err(poison_error)?;
+        Ok(Mutex::new(
+            value
+                .f_map_tensors(mapper)
+                .at(PathSegment::Deref("Mutex"))?,
+        ))
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        let value = self.into_inner().map_err(poison_error)?;
+        Ok(Mutex::new(
+            value
+                .f_map_tensors_into(mapper)
+                .at(PathSegment::Deref("Mutex"))?,
+        ))
+    }
+}
+
+/// Blocks until the read lock is acquired, and returns an error if the lock
+/// is poisoned.
+impl<T> TensorLike for RwLock<T>
+where
+    T: TensorLike,
+{
+    fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        let value = self.read().map_err(poison_error)?;
+        Ok(RwLock::new(
+            value
+                .f_map_tensors(mapper)
+                .at(PathSegment::Deref("RwLock"))?,
+        ))
+    }
+
+    fn f_map_tensors_into(self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+        let value = self.into_inner().map_err(poison_error)?;
+        Ok(RwLock::new(
+            value
+                .f_map_tensors_into(mapper)
+                .at(PathSegment::Deref("RwLock"))?,
+        ))
+    }
+}
+
+fn poison_error<E>(err: E) -> TchError
+where
+    E: Display,
+{
+    TchError::Torch(format!("cannot lock: {}", err))
+}
+
+// aliasing
+
+/// Records tensors transferred by [f_to_device_aliased](TensorLike::f_to_device_aliased).
+///
+/// Two tensors are considered aliases if they are on the same device and
+/// have the same kind, data pointer, shape and strides. The map keeps a
+/// shallow clone of each source tensor, so the recorded storages stay alive
+/// as long as the map does.
+///
+/// `Rc` and `Arc` pointers are recorded by their addresses, so that pointers
+/// to one allocation map to one shared result. The map holds weak pointers
+/// to them, which keep the addresses from being reused without keeping the
+/// values alive.
+#[derive(Debug, Default)]
+pub struct AliasMap {
+    tensors: HashMap<(AliasKey, Conversion), (Tensor, Tensor)>,
+    pointers: PointerMap,
+}
+
+impl AliasMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tensors.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tensors.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.tensors.clear();
+        self.pointers.clear();
+    }
+
+    /// Converts the tensor once per conversion and returns the recorded
+    /// result for its aliases.
+    fn f_get_or_insert_with(
+        &mut self,
+        tensor: &Tensor,
+        conversion: Conversion,
+    ) -> Result<Tensor, TchError> {
+        let key = match AliasKey::new(tensor) {
+            Some(key) => (key, conversion),
+            None => return conversion.f_apply(tensor),
+        };
+
+        if let Some((_, output)) = self.tensors.get(&key) {
+            return Ok(output.shallow_clone());
+        }
+
+        let output = conversion.f_apply(tensor)?;
+        self.tensors
+            .insert(key, (tensor.shallow_clone(), output.shallow_clone()));
+        Ok(output)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AliasKey {
+    data_ptr: usize,
+    device: Device,
+    kind: Kind,
+    size: Vec<i64>,
+    stride: Vec<i64>,
+}
+
+impl AliasKey {
+    /// Returns `None` for tensors without storage, which never alias.
+    fn new(tensor: &Tensor) -> Option<Self> {
+        if !tensor.defined() {
+            return None;
+        }
+
+        let data_ptr = tensor.data_ptr() as usize;
+        if data_ptr == 0 {
+            return None;
+        }
+
+        Some(Self {
+            data_ptr,
+            device: tensor.device(),
+            kind: tensor.kind(),
+            size: tensor.size(),
+            stride: tensor.stride(),
+        })
+    }
+}
+
+/// Records shared pointers mapped within one call or one [AliasMap].
+#[derive(Debug, Default)]
+struct PointerMap {
+    entries: HashMap<(PointerKey, Conversion), PointerEntry>,
+}
+
+impl PointerMap {
+    /// Returns the recorded output of the pointer, unless it has been
+    /// dropped since.
+    fn get<P>(&self, source: &P, conversion: Conversion) -> Option<P>
+    where
+        P: SharedPointer,
+    {
+        let entry = self.entries.get(&(PointerKey::new(source), conversion))?;
+        // SAFETY: the entry holds weak pointers created by
+        // SharedPointer::downgrade_raw() for a P at the same address. The
+        // source weak pointer keeps the allocation, and thus the address,
+        // from being reused, so the entry was recorded for this very
+        // allocation.
+        unsafe { P::upgrade_raw(entry.output) }
+    }
+
+    fn insert<P>(&mut self, source: &P, conversion: Conversion, output: &P)
+    where
+        P: SharedPointer,
+    {
+        let entry = PointerEntry {
+            source: source.downgrade_raw(),
+            output: output.downgrade_raw(),
+            drop_weak: P::drop_weak_raw,
+        };
+        self.entries
+            .insert((PointerKey::new(source), conversion), entry);
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct PointerKey {
+    type_name: &'static str,
+    address: usize,
+}
+
+impl PointerKey {
+    fn new<P>(pointer: &P) -> Self
+    where
+        P: SharedPointer,
+    {
+        Self {
+            type_name: any::type_name::<P>(),
+            address: pointer.address(),
+        }
+    }
+}
+
+/// Type-erased weak pointers to a source allocation and to its output.
+/// They are stored as raw pointers, since `TypeId` and `dyn Any` would
+/// restrict the pointee types to `'static` ones.
+#[derive(Debug)]
+struct PointerEntry {
+    source: *const (),
+    output: *const (),
+    drop_weak: unsafe fn(*const ()),
+}
+
+impl Drop for PointerEntry {
+    fn drop(&mut self) {
+        // SAFETY: both pointers come from downgrade_raw() of the pointer
+        // type drop_weak was instantiated with, and are released only here.
+        unsafe {
+            (self.drop_weak)(self.source);
+            (self.drop_weak)(self.output);
+        }
+    }
+}
+
+/// Reference-counted pointers recorded by [PointerMap].
+trait SharedPointer: Sized {
+    fn address(&self) -> usize;
+
+    /// Creates a weak pointer and leaks it as a raw pointer.
+    fn downgrade_raw(&self) -> *const ();
+
+    /// Upgrades a weak pointer leaked by [downgrade_raw](SharedPointer::downgrade_raw)
+    /// without releasing it.
+    ///
+    /// # Safety
+    /// `ptr` must come from `downgrade_raw()` of the same pointer type and
+    /// not be released yet.
+    unsafe fn upgrade_raw(ptr: *const ()) -> Option<Self>;
+
+    /// Releases a weak pointer leaked by [downgrade_raw](SharedPointer::downgrade_raw).
+    ///
+    /// # Safety
+    /// `ptr` must come from `downgrade_raw()` of the same pointer type and
+    /// not be released yet.
+    unsafe fn drop_weak_raw(ptr: *const ());
+}
+
+impl<T> SharedPointer for Rc<T> {
+    fn address(&self) -> usize {
+        Rc::as_ptr(self) as usize
+    }
+
+    fn downgrade_raw(&self) -> *const () {
+        rc::Weak::into_raw(Rc::downgrade(self)) as *const ()
+    }
+
+    unsafe fn upgrade_raw(ptr: *const ()) -> Option<Self> {
+        ManuallyDrop::new(rc::Weak::from_raw(ptr as *const T)).upgrade()
+    }
+
+    unsafe fn drop_weak_raw(ptr: *const ()) {
+        drop(rc::Weak::from_raw(ptr as *const T));
+    }
+}
+
+impl<T> SharedPointer for Arc<T> {
+    fn address(&self) -> usize {
+        Arc::as_ptr(self) as usize
+    }
+
+    fn downgrade_raw(&self) -> *const () {
+        sync::Weak::into_raw(Arc::downgrade(self)) as *const ()
+    }
+
+    unsafe fn upgrade_raw(ptr: *const ()) -> Option<Self> {
+        ManuallyDrop::new(sync::Weak::from_raw(ptr as *const T)).upgrade()
+    }
+
+    unsafe fn drop_weak_raw(ptr: *const ()) {
+        drop(sync::Weak::from_raw(ptr as *const T));
+    }
+}
+
+// memory format
+
+/// The memory layout of tensor elements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MemoryFormat {
+    /// Row-major layout, applies to tensors of any dimension.
+    Contiguous,
+    /// NHWC layout, applies to 4-D tensors.
+    ChannelsLast,
+    /// NDHWC layout, applies to 5-D tensors.
+    ChannelsLast3d,
+}
+
+impl MemoryFormat {
+    /// Returns the permutation from the logical dimension order to the
+    /// memory order, or `None` if the format does not apply to tensors of
+    /// `dim` dimensions.
+    fn permutation(&self, dim: usize) -> Option<&'static [i64]> {
+        match (self, dim) {
+            (Self::Contiguous, _) => Some(&[]),
+            (Self::ChannelsLast, 4) => Some(&[0, 2, 3, 1]),
+            (Self::ChannelsLast3d, 5) => Some(&[0, 2, 3, 4, 1]),
+            _ => None,
+        }
+    }
+
+    fn f_apply(&self, tensor: &Tensor) -> Result<Tensor, TchError> {
+        if !tensor.defined() {
+            return Ok(tensor.shallow_clone());
+        }
+
+        match self.permutation(tensor.dim()) {
+            Some([]) => tensor.f_contiguous(),
+            Some(permutation) => {
+                // make the permuted view contiguous, then restore the logical
+                // dimension order without moving the elements
+                let mut inverse = vec![0; permutation.len()];
+                permutation
+                    .iter()
+                    .enumerate()
+                    .for_each(|(index, &dim)| inverse[dim as usize] = index as i64);
+
+                tensor
+                    .f_permute(permutation)?
+                    .f_contiguous()?
+                    .f_permute(&inverse)
+            }
+            None => Ok(tensor.shallow_clone()),
+        }
+    }
+}
+
+// error
+
+/// The error returned by fallible [TensorLike] methods.
+///
+/// It wraps the [TchError] raised by a tensor operation along with the path
+/// to the failing tensor, such as `.encoder.layers[2].weight` or
+/// `["images"]<Rc>`. Use `?` or [From] to convert it into a [TchError],
+/// whose message then includes the path.
+#[derive(Debug)]
+pub struct TensorLikeError {
+    /// The path from the outermost value, with the outermost segment first.
+    path: Vec<PathSegment>,
+    error: TchError,
+}
+
+/// A step in the path of a [TensorLikeError].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PathSegment {
+    /// A named struct field, displayed as `.name`.
+    Field(&'static str),
+    /// A tuple or tuple struct field, displayed as `.0`.
+    TupleField(usize),
+    /// An element of a sequence, displayed as `[0]`.
+    Index(usize),
+    /// A map entry, displayed as `[key]` with the key in debug format.
+    Key(String),
+    /// An enum variant, displayed as `::Name`. `Option`, `Result` and
+    /// `Either` record `Some`, `Ok`, `Left` and `Right`.
+    Variant(&'static str),
+    /// The value inside a pointer, cell or lock of the named type, such as
+    /// `Rc` or `RefCell`, displayed as `<Rc>`.
+    Deref(&'static str),
+}
+
+impl TensorLikeError {
+    pub fn new(error: TchError) -> Self {
+        Self {
+            path: vec![],
+            error,
+        }
+    }
+
+    /// Prepends `segment` to the path. Impls call it on errors coming from
+    /// their fields, so the path is built from the innermost value outwards.
+    pub fn at(mut self, segment: PathSegment) -> Self {
+        self.path.insert(0, segment);
+        self
+    }
+
+    pub fn path(&self) -> &[PathSegment] {
+        &self.path
+    }
+
+    pub fn tch_error(&self) -> &TchError {
+        &self.error
+    }
+
+    pub fn into_tch_error(self) -> TchError {
+        self.into()
+    }
+
+    fn path_string(&self) -> String {
+        self.path
+            .iter()
+            .map(|segment| segment.to_string())
+            .collect()
+    }
+}
+
+impl Display for TensorLikeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            Display::fmt(&self.error, f)
+        } else {
+            write!(f, "at {}: {}", self.path_string(), self.error)
+        }
+    }
+}
+
+impl std::error::Error for TensorLikeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+impl From<TchError> for TensorLikeError {
+    fn from(error: TchError) -> Self {
+        Self::new(error)
+    }
+}
+
+/// Keeps the variant of the wrapped error and prefixes its message with the
+/// path. Variants without a message are returned as is.
+impl From<TensorLikeError> for TchError {
+    fn from(err: TensorLikeError) -> Self {
+        if err.path.is_empty() {
+            return err.error;
+        }
+
+        let path = err.path_string();
+        let prefix = |msg: String| format!("at {}: {}", path, msg);
+
+        match err.error {
+            TchError::Convert(msg) => TchError::Convert(prefix(msg)),
+            TchError::FileFormat(msg) => TchError::FileFormat(prefix(msg)),
+            TchError::Kind(msg) => TchError::Kind(prefix(msg)),
+            TchError::Shape(msg) => TchError::Shape(prefix(msg)),
+            TchError::Torch(msg) => TchError::Torch(prefix(msg)),
+            error => error,
+        }
+    }
+}
+
+impl Display for PathSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Field(name) => write!(f, ".{}", name),
+            Self::TupleField(index) => write!(f, ".{}", index),
+            Self::Index(index) => write!(f, "[{}]", index),
+            Self::Key(key) => write!(f, "[{}]", key),
+            Self::Variant(name) => write!(f, "::{}", name),
+            Self::Deref(name) => write!(f, "<{}>", name),
+        }
+    }
+}
+
+/// Attaches a path segment to the error of a result.
+trait ResultExt<T> {
+    fn at(self, segment: PathSegment) -> Result<T, TensorLikeError>;
+}
+
+impl<T, E> ResultExt<T> for Result<T, E>
+where
+    E: Into<TensorLikeError>,
+{
+    fn at(self, segment: PathSegment) -> Result<T, TensorLikeError> {
+        self.map_err(|err| err.into().at(segment))
+    }
+}
+
+/// Builds the path segment of a map entry. Formatting the key is costly, so
+/// impls call it on errors only.
+fn key_segment<K>(key: &K) -> PathSegment
+where
+    K: Debug,
+{
+    PathSegment::Key(format!("{:?}", key))
+}
+
+// ivalue
+
+/// Converts a value into an [IValue] to be passed to TorchScript modules.
+///
+/// Tuples and derived types become [IValue::Tuple] (or [IValue::GenericDict]
+/// with `#[ivalue(dict)]`), sequences become lists, maps become
+/// [IValue::GenericDict] and `None` becomes [IValue::None].
+pub trait IntoIValue {
+    fn into_ivalue(self) -> IValue;
+
+    /// Converts the elements of a sequence into a list. It returns an
+    /// [IValue::GenericList] by default, which tch creates as `List[Any]`
+    /// and TorchScript rejects for typed list parameters, so element types
+    /// with a typed list counterpart, such as [IValue::IntList] for `i64`,
+    /// override it.
+    fn into_ivalue_list(values: Vec<Self>) -> IValue
+    where
+        Self: Sized,
+    {
+        IValue::GenericList(values.into_iter().map(Self::into_ivalue).collect())
+    }
+}
+
+/// Converts an [IValue] returned by TorchScript modules back into a value,
+/// the reverse of [IntoIValue].
+pub trait TryFromIValue
+where
+    Self: Sized,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError>;
+}
+
+impl IntoIValue for IValue {
+    fn into_ivalue(self) -> IValue {
+        self
+    }
+}
+
+impl TryFromIValue for IValue {
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        Ok(value)
+    }
+}
+
+impl IntoIValue for Tensor {
+    fn into_ivalue(self) -> IValue {
+        IValue::Tensor(self)
+    }
+
+    fn into_ivalue_list(values: Vec<Self>) -> IValue {
+        IValue::TensorList(values)
+    }
+}
+
+impl TryFromIValue for Tensor {
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        match value {
+            IValue::Tensor(tensor) => Ok(tensor),
+            value => Err(unexpected_ivalue("Tensor", &value)),
+        }
+    }
+}
+
+macro_rules! impl_ivalue_for_int {
+    ($name:ty) => {
+        impl IntoIValue for $name {
+            fn into_ivalue(self) -> IValue {
+                IValue::Int(self.into())
+            }
+
+            fn into_ivalue_list(values: Vec<Self>) -> IValue {
+                IValue::IntList(values.into_iter().map(i64::from).collect())
+            }
+        }
+
+        impl TryFromIValue for $name {
+            fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+                match value {
+                    IValue::Int(value) => value
+                        .try_into()
+                        .map_err(|_| TchError::Convert(format!("integer {} out of range", value))),
+                    value => Err(unexpected_ivalue("Int", &value)),
+                }
+            }
+        }
+    };
+}
+
+impl_ivalue_for_int!(u8);
+impl_ivalue_for_int!(u16);
+impl_ivalue_for_int!(u32);
+impl_ivalue_for_int!(i8);
+impl_ivalue_for_int!(i16);
+impl_ivalue_for_int!(i32);
+impl_ivalue_for_int!(i64);
+
+impl IntoIValue for f64 {
+    fn into_ivalue(self) -> IValue {
+        IValue::Double(self)
+    }
+
+    fn into_ivalue_list(values: Vec<Self>) -> IValue {
+        IValue::DoubleList(values)
+    }
+}
+
+impl TryFromIValue for f64 {
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        match value {
+            IValue::Double(value) => Ok(value),
+            value => Err(unexpected_ivalue("Double", &value)),
+        }
+    }
+}
+
+impl IntoIValue for f32 {
+    fn into_ivalue(self) -> IValue {
+        IValue::Double(self.into())
+    }
+
+    fn into_ivalue_list(values: Vec<Self>) -> IValue {
+        IValue::DoubleList(values.into_iter().map(f64::from).collect())
+    }
+}
+
+impl TryFromIValue for f32 {
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        Ok(f64::try_from_ivalue(value)? as f32)
+    }
+}
+
+impl IntoIValue for bool {
+    fn into_ivalue(self) -> IValue {
+        IValue::Bool(self)
+    }
+
+    fn into_ivalue_list(values: Vec<Self>) -> IValue {
+        IValue::BoolList(values)
+    }
+}
+
+impl TryFromIValue for bool {
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        match value {
+            IValue::Bool(value) => Ok(value),
+            value => Err(unexpected_ivalue("Bool", &value)),
+        }
+    }
+}
+
+impl IntoIValue for String {
+    fn into_ivalue(self) -> IValue {
+        IValue::String(self)
+    }
+
+    fn into_ivalue_list(values: Vec<Self>) -> IValue {
+        IValue::StringList(values)
+    }
+}
+
+impl TryFromIValue for String {
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        match value {
+            IValue::String(value) => Ok(value),
+            value => Err(unexpected_ivalue("String", &value)),
+        }
+    }
+}
+
+impl IntoIValue for () {
+    fn into_ivalue(self) -> IValue {
+        IValue::None
+    }
+}
+
+impl TryFromIValue for () {
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        match value {
+            IValue::None => Ok(()),
+            value => Err(unexpected_ivalue("None", &value)),
+        }
+    }
+}
+
+impl<T> IntoIValue for Box<T>
+where
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        (*self).into_ivalue()
+    }
+
+    fn into_ivalue_list(values: Vec<Self>) -> IValue {
+        T::into_ivalue_list(values.into_iter().map(|value| *value).collect())
+    }
+}
+
+impl<T> TryFromIValue for Box<T>
+where
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        Ok(Box::new(T::try_from_ivalue(value)?))
+    }
+}
+
+impl<T> IntoIValue for Option<T>
+where
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        match self {
+            Some(value) => value.into_ivalue(),
+            None => IValue::None,
+        }
+    }
+}
+
+impl<T> TryFromIValue for Option<T>
+where
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        match value {
+            IValue::None => Ok(None),
+            value => Ok(Some(T::try_from_ivalue(value)?)),
+        }
+    }
+}
+
+impl<T> IntoIValue for Vec<T>
+where
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        T::into_ivalue_list(self)
+    }
+}
+
+impl<T> TryFromIValue for Vec<T>
+where
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        __private::unpack_list(value)?
+            .into_iter()
+            .map(T::try_from_ivalue)
+            .collect()
+    }
+}
+
+impl<T> IntoIValue for VecDeque<T>
+where
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        T::into_ivalue_list(self.into())
+    }
+}
+
+impl<T> TryFromIValue for VecDeque<T>
+where
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        Ok(Vec::try_from_ivalue(value)?.into())
+    }
+}
+
+impl<T, const N: usize> IntoIValue for [T; N]
+where
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        T::into_ivalue_list(self.into())
+    }
+}
+
+impl<T, const N: usize> TryFromIValue for [T; N]
+where
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        let values = __private::unpack_list(value)?;
+        if values.len() != N {
+            return Err(TchError::Kind(format!(
+                "unable to unpack ivalue, expected a list of len {} got {}",
+                N,
+                values.len()
+            )));
+        }
+        try_collect_array(values.into_iter().map(T::try_from_ivalue))
+    }
+}
+
+impl<K, T> IntoIValue for HashMap<K, T>
+where
+    K: IntoIValue,
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        IValue::GenericDict(
+            self.into_iter()
+                .map(|(key, value)| (key.into_ivalue(), value.into_ivalue()))
+                .collect(),
+        )
+    }
+}
+
+impl<K, T> TryFromIValue for HashMap<K, T>
+where
+    K: TryFromIValue + Eq + Hash,
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        __private::unpack_dict(value)?
+            .into_iter()
+            .map(|(key, value)| Ok((K::try_from_ivalue(key)?, T::try_from_ivalue(value)?)))
+            .collect()
+    }
+}
+
+impl<K, T> IntoIValue for BTreeMap<K, T>
+where
+    K: IntoIValue,
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        IValue::GenericDict(
+            self.into_iter()
+                .map(|(key, value)| (key.into_ivalue(), value.into_ivalue()))
+                .collect(),
+        )
+    }
+}
+
+impl<K, T> TryFromIValue for BTreeMap<K, T>
+where
+    K: TryFromIValue + Ord,
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        __private::unpack_dict(value)?
+            .into_iter()
+            .map(|(key, value)| Ok((K::try_from_ivalue(key)?, T::try_from_ivalue(value)?)))
+            .collect()
+    }
+}
+
+#[cfg(feature = "smallvec")]
+impl<A> IntoIValue for SmallVec<A>
+where
+    A: smallvec::Array,
+    A::Item: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        A::Item::into_ivalue_list(self.into_vec())
+    }
+}
+
+#[cfg(feature = "smallvec")]
+impl<A> TryFromIValue for SmallVec<A>
+where
+    A: smallvec::Array,
+    A::Item: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        Ok(Vec::try_from_ivalue(value)?.into())
+    }
+}
+
+#[cfg(feature = "arrayvec")]
+impl<T, const CAP: usize> IntoIValue for ArrayVec<T, CAP>
+where
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        T::into_ivalue_list(self.into_iter().collect())
+    }
+}
+
+#[cfg(feature = "arrayvec")]
+impl<T, const CAP: usize> TryFromIValue for ArrayVec<T, CAP>
+where
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        let values = __private::unpack_list(value)?;
+        if values.len() > CAP {
+            return Err(TchError::Kind(format!(
+                "unable to unpack ivalue, expected a list of at most len {} got {}",
+                CAP,
+                values.len()
+            )));
+        }
+        values.into_iter().map(T::try_from_ivalue).collect()
+    }
+}
+
+/// Keeps the order of the entries.
+#[cfg(feature = "indexmap")]
+impl<K, T> IntoIValue for IndexMap<K, T>
+where
+    K: IntoIValue,
+    T: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        IValue::GenericDict(
+            self.into_iter()
+                .map(|(key, value)| (key.into_ivalue(), value.into_ivalue()))
+                .collect(),
+        )
+    }
+}
+
+#[cfg(feature = "indexmap")]
+impl<K, T> TryFromIValue for IndexMap<K, T>
+where
+    K: TryFromIValue + Eq + Hash,
+    T: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        __private::unpack_dict(value)?
+            .into_iter()
+            .map(|(key, value)| Ok((K::try_from_ivalue(key)?, T::try_from_ivalue(value)?)))
+            .collect()
+    }
+}
+
+/// Converts the contained value as is, like a TorchScript `Union`.
+#[cfg(feature = "either")]
+impl<L, R> IntoIValue for Either<L, R>
+where
+    L: IntoIValue,
+    R: IntoIValue,
+{
+    fn into_ivalue(self) -> IValue {
+        match self {
+            Either::Left(value) => value.into_ivalue(),
+            Either::Right(value) => value.into_ivalue(),
+        }
+    }
+}
+
+/// Tries `L` first and then `R`. Objects cannot be copied for the second
+/// attempt and are only converted into `L`.
+#[cfg(feature = "either")]
+impl<L, R> TryFromIValue for Either<L, R>
+where
+    L: TryFromIValue,
+    R: TryFromIValue,
+{
+    fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+        let copy = shallow_clone_ivalue(&value);
+
+        match (L::try_from_ivalue(value), copy) {
+            (Ok(value), _) => Ok(Either::Left(value)),
+            (Err(_), Some(copy)) => Ok(Either::Right(R::try_from_ivalue(copy)?)),
+            (Err(err), None) => Err(err),
+        }
+    }
+}
+
+macro_rules! impl_ivalue_for_tuple {
+    ($len:literal; $($name:ident: $var:ident),+) => {
+        impl<$($name),+> IntoIValue for ($($name,)+)
+        where
+            $($name: IntoIValue,)+
+        {
+            fn into_ivalue(self) -> IValue {
+                let ($($var,)+) = self;
+                IValue::Tuple(vec![$($var.into_ivalue()),+])
+            }
+        }
+
+        impl<$($name),+> TryFromIValue for ($($name,)+)
+        where
+            $($name: TryFromIValue,)+
+        {
+            fn try_from_ivalue(value: IValue) -> Result<Self, TchError> {
+                let mut values = __private::unpack_tuple(value, $len)?.into_iter();
+                Ok(($($name::try_from_ivalue(values.next().unwrap())?,)+))
+            }
+        }
+    };
+}
+
+impl_ivalue_for_tuple!(1; T1: v1);
+impl_ivalue_for_tuple!(2; T1: v1, T2: v2);
+impl_ivalue_for_tuple!(3; T1: v1, T2: v2, T3: v3);
+impl_ivalue_for_tuple!(4; T1: v1, T2: v2, T3: v3, T4: v4);
+impl_ivalue_for_tuple!(5; T1: v1, T2: v2, T3: v3, T4: v4, T5: v5);
+impl_ivalue_for_tuple!(6; T1: v1, T2: v2, T3: v3, T4: v4, T5: v5, T6: v6);
+impl_ivalue_for_tuple!(7; T1: v1, T2: v2, T3: v3, T4: v4, T5: v5, T6: v6, T7: v7);
+impl_ivalue_for_tuple!(8; T1: v1, T2: v2, T3: v3, T4: v4, T5: v5, T6: v6, T7: v7, T8: v8);
+impl_ivalue_for_tuple!(9; T1: v1, T2: v2, T3: v3, T4: v4, T5: v5, T6: v6, T7: v7, T8: v8, T9: v9);
+impl_ivalue_for_tuple!(10; T1: v1, T2: v2, T3: v3, T4: v4, T5: v5, T6: v6, T7: v7, T8: v8, T9: v9, T10: v10);
+impl_ivalue_for_tuple!(11; T1: v1, T2: v2, T3: v3, T4: v4, T5: v5, T6: v6, T7: v7, T8: v8, T9: v9, T10: v10, T11: v11);
+impl_ivalue_for_tuple!(12; T1: v1, T2: v2, T3: v3, T4: v4, T5: v5, T6: v6, T7: v7, T8: v8, T9: v9, T10: v10, T11: v11, T12: v12);
+
+/// Copies the value with tensors shallow cloned, or returns `None` if it
+/// contains objects, which cannot be copied.
+#[cfg(feature = "either")]
+fn shallow_clone_ivalue(value: &IValue) -> Option<IValue> {
+    let copy_all = |values: &[IValue]| -> Option<Vec<IValue>> {
+        values.iter().map(shallow_clone_ivalue).collect()
+    };
+
+    let copy = match value {
+        IValue::None => IValue::None,
+        IValue::Tensor(tensor) => IValue::Tensor(tensor.shallow_clone()),
+        IValue::Double(value) => IValue::Double(*value),
+        IValue::Int(value) => IValue::Int(*value),
+        IValue::Bool(value) => IValue::Bool(*value),
+        IValue::Tuple(values) => IValue::Tuple(copy_all(values)?),
+        IValue::IntList(values) => IValue::IntList(values.clone()),
+        IValue::DoubleList(values) => IValue::DoubleList(values.clone()),
+        IValue::BoolList(values) => IValue::BoolList(values.clone()),
+        IValue::String(value) => IValue::String(value.clone()),
+        IValue::StringList(values) => IValue::StringList(values.clone()),
+        IValue::TensorList(values) => {
+            IValue::TensorList(values.iter().map(Tensor::shallow_clone).collect())
+        }
+        IValue::GenericList(values) => IValue::GenericList(copy_all(values)?),
+        IValue::GenericDict(entries) => IValue::GenericDict(
+            entries
+                .iter()
+                .map(|(key, value)| {
+                    Some((shallow_clone_ivalue(key)?, shallow_clone_ivalue(value)?))
+                })
+                .collect::<Option<_>>()?,
+        ),
+        _ => return None,
+    };
+    Some(copy)
+}
+
+fn unexpected_ivalue(expected: &str, value: &IValue) -> TchError {
+    TchError::Kind(format!(
+        "unable to unpack ivalue, expected {} got {}",
+        expected,
+        ivalue_type_name(value)
+    ))
+}
+
+fn ivalue_type_name(value: &IValue) -> &'static str {
+    match value {
+        IValue::None => "None",
+        IValue::Tensor(_) => "Tensor",
+        IValue::Double(_) => "Double",
+        IValue::Int(_) => "Int",
+        IValue::Bool(_) => "Bool",
+        IValue::Tuple(_) => "Tuple",
+        IValue::IntList(_) => "IntList",
+        IValue::DoubleList(_) => "DoubleList",
+        IValue::BoolList(_) => "BoolList",
+        IValue::String(_) => "String",
+        IValue::StringList(_) => "StringList",
+        IValue::TensorList(_) => "TensorList",
+        IValue::GenericList(_) => "GenericList",
+        IValue::GenericDict(_) => "GenericDict",
+        IValue::Object(_) => "Object",
+        _ => "unknown",
+    }
+}
+
+/// Helpers used by the code generated by the derive macros.
+#[doc(hidden)]
+pub mod __private {
+    use super::unexpected_ivalue;
+    use tch::{IValue, TchError};
+
+    /// Unpacks a tuple or a list of exactly `len` elements.
+    pub fn unpack_tuple(value: IValue, len: usize) -> Result<Vec<IValue>, TchError> {
+        let values = match value {
+            IValue::Tuple(values) | IValue::GenericList(values) => values,
+            value => return Err(unexpected_ivalue("Tuple", &value)),
+        };
+
+        if values.len() != len {
+            return Err(TchError::Kind(format!(
+                "unable to unpack ivalue, expected a tuple of len {} got {}",
+                len,
+                values.len()
+            )));
+        }
+        Ok(values)
+    }
+
+    /// Unpacks any kind of list into its elements.
+    pub fn unpack_list(value: IValue) -> Result<Vec<IValue>, TchError> {
+        let values = match value {
+            IValue::Tuple(values) | IValue::GenericList(values) => values,
+            IValue::IntList(values) => values.into_iter().map(IValue::Int).collect(),
+            IValue::DoubleList(values) => values.into_iter().map(IValue::Double).collect(),
+            IValue::BoolList(values) => values.into_iter().map(IValue::Bool).collect(),
+            IValue::StringList(values) => values.into_iter().map(IValue::String).collect(),
+            IValue::TensorList(values) => values.into_iter().map(IValue::Tensor).collect(),
+            value => return Err(unexpected_ivalue("GenericList", &value)),
+        };
+        Ok(values)
+    }
+
+    pub fn unpack_dict(value: IValue) -> Result<Vec<(IValue, IValue)>, TchError> {
+        match value {
+            IValue::GenericDict(entries) => Ok(entries),
+            value => Err(unexpected_ivalue("GenericDict", &value)),
+        }
+    }
+
+    /// Removes the entry with the string key `name` from dictionary entries.
+    pub fn take_dict_entry(
+        entries: &mut Vec<(IValue, IValue)>,
+        name: &str,
+    ) -> Result<IValue, TchError> {
+        let index = entries
+            .iter()
+            .position(|(key, _)| matches!(key, IValue::String(key) if key == name))
+            .ok_or_else(|| {
+                TchError::Kind(format!(
+                    "unable to unpack ivalue, missing dictionary key \"{}\"",
+                    name
+                ))
+            })?;
+        Ok(entries.swap_remove(index).1)
     }
 }
@@ -0,0 +1,11 @@
+use tch::Tensor;
+use tch_tensor_like::TensorLike;
+
+#[derive(TensorLike)]
+struct Input {
+    images: Tensor,
+    #[tensor_like(clone, copy)]
+    step: usize,
+}
+
+fn main() {}
@@ -0,0 +1,11 @@
+use tch::Tensor;
+use tch_tensor_like::TensorLike;
+
+#[derive(TensorLike)]
+struct Input {
+    #[tensor_like(kind = "keep")]
+    #[tensor_like(kind = "Int64")]
+    labels: Tensor,
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use tch::Tensor;
+use tch_tensor_like::TensorLike;
+
+struct Counter(usize);
+
+#[derive(TensorLike)]
+struct Input {
+    images: Tensor,
+    counter: Counter,
+}
+
+fn main() {}
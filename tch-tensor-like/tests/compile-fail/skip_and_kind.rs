@@ -0,0 +1,11 @@
+use tch::Tensor;
+use tch_tensor_like::TensorLike;
+
+#[derive(TensorLike)]
+struct Input {
+    images: Tensor,
+    #[tensor_like(skip, kind = "Int64")]
+    labels: Tensor,
+}
+
+fn main() {}
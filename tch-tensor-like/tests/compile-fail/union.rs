@@ -0,0 +1,9 @@
+use tch_tensor_like::TensorLike;
+
+#[derive(TensorLike)]
+union Value {
+    int: i64,
+    float: f64,
+}
+
+fn main() {}
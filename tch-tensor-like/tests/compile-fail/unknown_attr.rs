@@ -0,0 +1,10 @@
+use tch::Tensor;
+use tch_tensor_like::TensorLike;
+
+#[derive(TensorLike)]
+struct Input {
+    #[tensor_like(flatten)]
+    images: Tensor,
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+use tch::Tensor;
+use tch_tensor_like::TensorLike;
+
+mod foreign {
+    pub struct Foreign(pub tch::Tensor);
+
+    pub fn shallow_clone(value: &Foreign) -> Foreign {
+        Foreign(value.0.shallow_clone())
+    }
+}
+
+#[derive(TensorLike)]
+struct Input {
+    images: Tensor,
+    #[tensor_like(with = "foreign")]
+    foreign: foreign::Foreign,
+}
+
+fn main() {}
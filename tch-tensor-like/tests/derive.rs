@@ -1,7 +1,20 @@
 use approx::assert_abs_diff_eq;
-use std::collections::{BTreeMap, HashMap, LinkedList, VecDeque};
-use tch::{kind::FLOAT_CPU, Device, Kind, Tensor};
-use tch_tensor_like::TensorLike;
+use std::{
+    borrow::Cow,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, LinkedList, VecDeque},
+    marker::PhantomData,
+    rc::Rc,
+    sync::{Arc, Mutex, RwLock},
+};
+use tch::{
+    kind::{FLOAT_CPU, INT64_CPU},
+    Device, IValue, Kind, TchError, Tensor,
+};
+use tch_tensor_like::{
+    AliasMap, IntoIValue, MemoryFormat, PathSegment, TensorLike, TensorLikeError, TensorMapper,
+    TryFromIValue,
+};
 
 #[test]
 fn shallow_clone_test() {
@@ -139,6 +152,851 @@ fn collections_test() {
         });
 }
 
+#[test]
+fn aliasing_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    let shared = Tensor::randn(&[2, 3], FLOAT_CPU);
+    let from = vec![
+        shared.shallow_clone(),
+        Tensor::randn(&[2, 3], FLOAT_CPU),
+        shared.shallow_clone(),
+    ];
+
+    let mut aliases = AliasMap::new();
+    let to = from.to_device_aliased(maybe_cuda, &mut aliases);
+    assert_eq!(aliases.len(), 2);
+
+    to.iter().for_each(|tensor| {
+        assert_eq!(tensor.device(), maybe_cuda);
+    });
+    assert_eq!(to[0].data_ptr(), to[2].data_ptr());
+    assert_ne!(to[0].data_ptr(), to[1].data_ptr());
+
+    // aliases are resolved across values sharing the same map
+    let other = Some(shared.shallow_clone()).to_device_aliased(maybe_cuda, &mut aliases);
+    assert_eq!(other.unwrap().data_ptr(), to[0].data_ptr());
+}
+
+#[test]
+fn detach_test() {
+    let mut from = (0..4)
+        .map(|index| (index, Tensor::randn(&[], FLOAT_CPU).set_requires_grad(true)))
+        .collect::<BTreeMap<_, _>>();
+
+    let to = from.detach();
+    to.values().for_each(|tensor| {
+        assert!(!tensor.requires_grad());
+    });
+    from.values().for_each(|tensor| {
+        assert!(tensor.requires_grad());
+    });
+
+    from.detach_();
+    from.values().for_each(|tensor| {
+        assert!(!tensor.requires_grad());
+    });
+}
+
+#[test]
+fn manual_impl_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    struct Encoder {
+        weight: Tensor,
+        layers: Vec<Tensor>,
+        name: String,
+    }
+
+    impl TensorLike for Encoder {
+        fn f_map_tensors(&self, mapper: &mut TensorMapper<'_>) -> Result<Self, TensorLikeError> {
+            Ok(Self {
+                weight: mapper.f_map(&self.weight)?,
+                layers: self.layers.f_map_tensors(mapper)?,
+                name: self.name.clone(),
+            })
+        }
+    }
+
+    let weight = Tensor::randn(&[2], FLOAT_CPU);
+    let from = Encoder {
+        weight: weight.shallow_clone(),
+        layers: vec![weight.shallow_clone()],
+        name: "encoder".into(),
+    };
+
+    // the provided methods are built on f_map_tensors
+    let to = from.to_device(maybe_cuda).to_kind(Kind::Double);
+    assert_eq!(to.weight.device(), maybe_cuda);
+    assert_eq!(to.weight.kind(), Kind::Double);
+    assert_eq!(to.layers[0].kind(), Kind::Double);
+    assert_eq!(to.name, "encoder");
+
+    let to = from.shallow_clone();
+    assert_eq!(to.weight.data_ptr(), weight.data_ptr());
+
+    let to = from.to_device_aliased(maybe_cuda, &mut AliasMap::new());
+    assert_eq!(to.weight.data_ptr(), to.layers[0].data_ptr());
+
+    let mut from = Encoder {
+        weight: Tensor::randn(&[2], FLOAT_CPU).set_requires_grad(true),
+        layers: vec![Tensor::randn(&[2], FLOAT_CPU).set_requires_grad(true)],
+        name: "encoder".into(),
+    };
+    let to = from.detach();
+    assert!(!to.weight.requires_grad());
+    assert!(!to.layers[0].requires_grad());
+
+    from.detach_();
+    assert!(!from.weight.requires_grad());
+    assert!(!from.layers[0].requires_grad());
+
+    // into_kind falls back to f_map_tensors
+    let to = from.into_kind(Kind::Double);
+    assert_eq!(to.weight.kind(), Kind::Double);
+    assert_eq!(to.name, "encoder");
+}
+
+#[test]
+fn requires_grad_test() {
+    let from = (
+        Tensor::randn(&[], FLOAT_CPU),
+        vec![Tensor::randn(&[], FLOAT_CPU)],
+        Tensor::zeros(&[], INT64_CPU),
+    );
+    assert!(!from.requires_grad_any());
+
+    let to = from.set_requires_grad(true);
+    assert!(to.requires_grad_any());
+    assert!(to.0.requires_grad());
+    assert!(to.1[0].requires_grad());
+    assert!(!to.2.requires_grad());
+
+    let to = to.set_requires_grad(false);
+    assert!(!to.requires_grad_any());
+}
+
+#[test]
+fn grads_test() {
+    let params = vec![
+        Tensor::randn(&[3], FLOAT_CPU).set_requires_grad(true),
+        Tensor::randn(&[3], FLOAT_CPU).set_requires_grad(true),
+    ];
+    let unused = Tensor::randn(&[3], FLOAT_CPU).set_requires_grad(true);
+
+    (&params[0] * &params[1]).sum(Kind::Float).backward();
+
+    let grads = params.grads();
+    assert_abs_diff_eq!(
+        f64::from((&grads[0] - &params[1]).abs().sum(Kind::Float)),
+        0.0
+    );
+    assert_abs_diff_eq!(
+        f64::from((&grads[1] - &params[0]).abs().sum(Kind::Float)),
+        0.0
+    );
+    assert!(!Some(unused).grads().unwrap().defined());
+
+    params.zero_grad();
+    params.grads().iter().for_each(|grad| {
+        assert_abs_diff_eq!(f64::from(grad.abs().sum(Kind::Float)), 0.0);
+    });
+}
+
+#[test]
+fn clip_grad_norm_test() {
+    let params = (
+        Tensor::ones(&[4], FLOAT_CPU).set_requires_grad(true),
+        Some(Tensor::ones(&[3, 4], FLOAT_CPU).set_requires_grad(true)),
+        Tensor::ones(&[2], FLOAT_CPU).set_requires_grad(true),
+    );
+
+    // gradients are 3 and 4 everywhere, while the last tensor has no gradient
+    (&params.0 * 3.0).sum(Kind::Float).backward();
+    (params.1.as_ref().unwrap() * 4.0)
+        .sum(Kind::Float)
+        .backward();
+
+    let mut count = 0;
+    params.for_each_tensor(&mut |_| count += 1);
+    assert_eq!(count, 3);
+
+    // no clipping
+    let norm = params.clip_grad_norm(f64::INFINITY, 2.0);
+    assert_abs_diff_eq!(norm, 228f64.sqrt(), epsilon = 1e-4);
+
+    // scales gradients to 0.75 and 1
+    let norm = params.clip_grad_norm(1.0, f64::INFINITY);
+    assert_abs_diff_eq!(norm, 4.0, epsilon = 1e-4);
+    assert_abs_diff_eq!(f64::from(params.0.grad().max()), 0.75, epsilon = 1e-4);
+
+    let norm = params.clip_grad_norm(1.0, 2.0);
+    assert_abs_diff_eq!(norm, 14.25f64.sqrt(), epsilon = 1e-4);
+
+    let norm = params.clip_grad_norm(f64::INFINITY, 2.0);
+    assert_abs_diff_eq!(norm, 1.0, epsilon = 1e-4);
+
+    // a parameter reachable twice is counted once
+    let shared = (params.0.shallow_clone(), params.0.shallow_clone());
+    assert_abs_diff_eq!(
+        shared.clip_grad_norm(f64::INFINITY, 2.0),
+        params.0.clip_grad_norm(f64::INFINITY, 2.0),
+        epsilon = 1e-4
+    );
+}
+
+#[test]
+fn deep_clone_test() {
+    let from = vec![
+        Tensor::zeros(&[3], FLOAT_CPU),
+        Tensor::zeros(&[2], FLOAT_CPU),
+    ];
+    let shallow = from.shallow_clone();
+    let mut deep = from.deep_clone();
+
+    deep.iter_mut().for_each(|tensor| {
+        let _ = tensor.fill_(1.0);
+    });
+    from.iter()
+        .zip(&shallow)
+        .zip(&deep)
+        .for_each(|((from, shallow), deep)| {
+            assert_eq!(from.data_ptr(), shallow.data_ptr());
+            assert_ne!(from.data_ptr(), deep.data_ptr());
+            assert_eq!(from.size(), deep.size());
+            assert_abs_diff_eq!(f64::from(from.sum(Kind::Float)), 0.0);
+        });
+}
+
+#[test]
+fn memory_format_test() {
+    let image = Tensor::randn(&[2, 3, 4, 5], FLOAT_CPU);
+    let mask = Tensor::randn(&[2, 4, 5], FLOAT_CPU);
+    let from = (image.shallow_clone(), mask.shallow_clone());
+
+    let to = from.to_memory_format(MemoryFormat::ChannelsLast);
+    assert_eq!(to.0.size(), vec![2, 3, 4, 5]);
+    assert_eq!(to.0.stride(), vec![60, 1, 15, 3]);
+    assert_abs_diff_eq!(f64::from((&to.0 - &image).abs().sum(Kind::Float)), 0.0);
+    assert_eq!(to.1.data_ptr(), mask.data_ptr());
+
+    let to = to.contiguous();
+    assert_eq!(to.0.stride(), vec![60, 20, 5, 1]);
+
+    let transposed = vec![mask.permute(&[0, 2, 1])];
+    assert_eq!(transposed[0].stride(), vec![20, 1, 5]);
+    assert_eq!(transposed.contiguous()[0].stride(), vec![20, 4, 1]);
+}
+
+#[test]
+fn float_kind_test() {
+    let from = vec![
+        Tensor::randn(&[], FLOAT_CPU),
+        Tensor::zeros(&[], INT64_CPU),
+        Tensor::zeros(&[], (Kind::Bool, Device::Cpu)),
+    ];
+    let to = from.to_float_kind(Kind::Half);
+
+    assert_eq!(to[0].kind(), Kind::Half);
+    assert_eq!(to[1].kind(), Kind::Int64);
+    assert_eq!(to[2].kind(), Kind::Bool);
+}
+
+#[test]
+fn array_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    let from = [
+        Tensor::randn(&[2], FLOAT_CPU),
+        Tensor::randn(&[2], FLOAT_CPU),
+        Tensor::randn(&[2], FLOAT_CPU),
+    ];
+    let to = from.to_device(maybe_cuda).to_kind(Kind::Double);
+
+    assert!(to
+        .iter()
+        .all(|tensor| tensor.device() == maybe_cuda && tensor.kind() == Kind::Double));
+
+    let empty: [Tensor; 0] = [];
+    assert!(empty.to_kind(Kind::Double).is_empty());
+}
+
+#[test]
+fn tuple_test() {
+    let tensor = || Tensor::randn(&[], FLOAT_CPU);
+    let from = (
+        tensor(),
+        1u8,
+        tensor(),
+        2i64,
+        tensor(),
+        tensor(),
+        tensor(),
+        Some(tensor()),
+        tensor(),
+        true,
+        tensor(),
+        vec![tensor()],
+    );
+    let to = from.to_kind(Kind::Double);
+
+    assert_eq!(to.0.kind(), Kind::Double);
+    assert_eq!(to.1, 1);
+    assert_eq!(to.7.unwrap().kind(), Kind::Double);
+    assert_eq!(to.11[0].kind(), Kind::Double);
+
+    let mut count = 0;
+    from.for_each_tensor(&mut |_| count += 1);
+    assert_eq!(count, 9);
+}
+
+#[test]
+fn std_types_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    let from: (Result<Tensor, String>, Result<Tensor, String>) = (
+        Ok(Tensor::randn(&[], FLOAT_CPU)),
+        Err("missing".to_string()),
+    );
+    let to = from.to_kind(Kind::Double);
+    assert_eq!(to.0.unwrap().kind(), Kind::Double);
+    assert_eq!(to.1.unwrap_err(), "missing");
+
+    let name: Cow<'static, str> = Cow::Borrowed("encoder");
+    assert_eq!(name.to_device(maybe_cuda), "encoder");
+
+    let from = ((), PhantomData::<Tensor>, "name".to_string(), 'x');
+    assert_eq!(
+        from.to_device(maybe_cuda),
+        ((), PhantomData, "name".to_string(), 'x')
+    );
+
+    // stored devices and kinds follow the conversions
+    let from = (Device::Cpu, Kind::Float, Kind::Int64);
+    assert_eq!(
+        from.to_device(maybe_cuda),
+        (maybe_cuda, Kind::Float, Kind::Int64)
+    );
+    assert_eq!(
+        from.to_kind(Kind::Half),
+        (Device::Cpu, Kind::Half, Kind::Half)
+    );
+    assert_eq!(
+        from.to_float_kind(Kind::Half),
+        (Device::Cpu, Kind::Half, Kind::Int64)
+    );
+}
+
+#[test]
+fn interior_mutability_test() {
+    let cache = RefCell::new(Some(Tensor::randn(&[], FLOAT_CPU)));
+    let to = cache.to_kind(Kind::Double);
+    assert_eq!(to.borrow().as_ref().unwrap().kind(), Kind::Double);
+
+    // conflicting borrows are reported as errors
+    let borrow_err = {
+        let _guard = cache.borrow_mut();
+        cache.f_to_kind(Kind::Double).unwrap_err()
+    };
+    assert!(borrow_err.to_string().contains("cannot borrow"));
+
+    let state = Mutex::new(Tensor::randn(&[], FLOAT_CPU));
+    let to = state.to_kind(Kind::Double);
+    assert_eq!(to.lock().unwrap().kind(), Kind::Double);
+
+    let state = RwLock::new(vec![Tensor::randn(&[], FLOAT_CPU)]);
+    let to = state.to_kind(Kind::Double);
+    assert_eq!(to.read().unwrap()[0].kind(), Kind::Double);
+
+    let step = Cell::new(Kind::Float);
+    assert_eq!(step.to_kind(Kind::Half).get(), Kind::Half);
+
+    // a lock held by another thread is waited for
+    let state = Arc::new(Mutex::new(Tensor::randn(&[], FLOAT_CPU)));
+    let guard = state.lock().unwrap();
+    let handle = std::thread::spawn({
+        let state = state.clone();
+        move || state.f_to_kind(Kind::Double).map(|_| ())
+    });
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    drop(guard);
+    assert!(handle.join().unwrap().is_ok());
+
+    // poisoned locks are reported as errors, distinct from borrow errors
+    let _ = std::thread::spawn({
+        let state = state.clone();
+        move || {
+            let _guard = state.lock().unwrap();
+            panic!("poison the lock");
+        }
+    })
+    .join();
+    let poison_err = state.f_to_kind(Kind::Double).unwrap_err();
+    assert!(poison_err.to_string().contains("cannot lock"));
+    assert_ne!(poison_err.to_string(), borrow_err.to_string());
+}
+
+#[test]
+#[cfg(feature = "smallvec")]
+fn smallvec_test() {
+    use smallvec::{smallvec, SmallVec};
+
+    let from: SmallVec<[Tensor; 4]> =
+        smallvec![Tensor::randn(&[], FLOAT_CPU), Tensor::randn(&[], FLOAT_CPU)];
+    let to = from.to_kind(Kind::Double);
+    assert!(to.iter().all(|tensor| tensor.kind() == Kind::Double));
+}
+
+#[test]
+#[cfg(feature = "arrayvec")]
+fn arrayvec_test() {
+    use arrayvec::ArrayVec;
+
+    let from: ArrayVec<Tensor, 4> = (0..3).map(|_| Tensor::randn(&[], FLOAT_CPU)).collect();
+    let to = from.to_kind(Kind::Double);
+    assert_eq!(to.len(), 3);
+    assert!(to.iter().all(|tensor| tensor.kind() == Kind::Double));
+}
+
+#[test]
+#[cfg(feature = "indexmap")]
+fn indexmap_test() {
+    use indexmap::IndexMap;
+
+    let from: IndexMap<String, Tensor> = ["p5", "p4", "p3"]
+        .iter()
+        .map(|name| (name.to_string(), Tensor::randn(&[], FLOAT_CPU)))
+        .collect();
+    let to = from.to_kind(Kind::Double);
+    assert!(to.keys().eq(from.keys()));
+    assert!(to.values().all(|tensor| tensor.kind() == Kind::Double));
+}
+
+#[test]
+#[cfg(feature = "either")]
+fn either_test() {
+    use either::Either;
+
+    let from: Vec<Either<Tensor, i64>> = vec![
+        Either::Left(Tensor::randn(&[], FLOAT_CPU)),
+        Either::Right(1),
+    ];
+    let to = from.to_kind(Kind::Double);
+    assert_eq!(to[0].as_ref().left().unwrap().kind(), Kind::Double);
+    assert_eq!(to[1].as_ref().right(), Some(&1));
+}
+
+#[test]
+#[cfg(feature = "nn")]
+fn nn_test() {
+    use tch::nn;
+
+    let maybe_cuda = Device::cuda_if_available();
+
+    let linear = nn::Linear {
+        ws: Tensor::randn(&[4, 2], FLOAT_CPU),
+        bs: Some(Tensor::randn(&[4], FLOAT_CPU)),
+    };
+    let to = linear.to_device(maybe_cuda).to_kind(Kind::Double);
+    assert_eq!(to.ws.device(), maybe_cuda);
+    assert_eq!(to.ws.kind(), Kind::Double);
+    assert_eq!(to.bs.unwrap().kind(), Kind::Double);
+
+    let state = nn::LSTMState((
+        Tensor::randn(&[1, 4], FLOAT_CPU),
+        Tensor::randn(&[1, 4], FLOAT_CPU),
+    ));
+    let to = state.to_kind(Kind::Double);
+    assert_eq!((to.0).0.kind(), Kind::Double);
+    assert_eq!((to.0).1.kind(), Kind::Double);
+
+    let state = nn::GRUState(Tensor::randn(&[1, 4], FLOAT_CPU));
+    assert_eq!(state.to_kind(Kind::Double).0.kind(), Kind::Double);
+
+    let _: nn::Id = nn::Id().to_device(maybe_cuda);
+}
+
+#[test]
+#[cfg(all(feature = "nn", feature = "derive"))]
+fn derive_nn_with_test() {
+    use tch::nn::{self, Module};
+
+    // layers with private fields are rebuilt by a with module
+    #[derive(TensorLike)]
+    struct Stem {
+        #[tensor_like(with = "stem_conv")]
+        conv: nn::Conv2D,
+        head: nn::Linear,
+    }
+
+    mod stem_conv {
+        use tch::{nn, Device};
+        use tch_tensor_like::{TensorLike, TensorLikeError, TensorMapper};
+
+        // the functions may return either TchError or TensorLikeError
+        pub fn f_map_tensors(
+            conv: &nn::Conv2D,
+            mapper: &mut TensorMapper<'_>,
+        ) -> Result<nn::Conv2D, TensorLikeError> {
+            let vs = nn::VarStore::new(Device::Cpu);
+            let mut output = nn::conv2d(vs.root(), 3, 4, 3, Default::default());
+            output.ws = mapper.f_map(&conv.ws)?;
+            output.bs = conv.bs.f_map_tensors(mapper)?;
+            Ok(output)
+        }
+    }
+
+    let vs = nn::VarStore::new(Device::Cpu);
+    let from = Stem {
+        conv: nn::conv2d(vs.root(), 3, 4, 3, Default::default()),
+        head: nn::linear(vs.root(), 4, 2, Default::default()),
+    };
+    let to = from.to_kind(Kind::Double);
+    assert_eq!(to.conv.ws.kind(), Kind::Double);
+    assert_eq!(to.head.ws.kind(), Kind::Double);
+
+    let input = Tensor::randn(&[1, 3, 5, 5], (Kind::Double, Device::Cpu));
+    assert_eq!(to.conv.forward(&input).size(), [1, 4, 3, 3]);
+}
+
+#[test]
+fn smart_pointer_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    let boxed = Box::new(Tensor::randn(&[2], FLOAT_CPU));
+    assert_eq!(boxed.to_kind(Kind::Double).kind(), Kind::Double);
+
+    // each conversion creates a new pointer
+    let shared = Rc::new(Tensor::randn(&[2], FLOAT_CPU));
+    let to = shared.to_device(maybe_cuda);
+    assert!(!Rc::ptr_eq(&shared, &to));
+    assert_eq!(to.device(), maybe_cuda);
+
+    // pointers to one allocation share the result within one value
+    let encoder = Rc::new(Tensor::randn(&[2], FLOAT_CPU).set_requires_grad(true));
+    let from = vec![
+        encoder.clone(),
+        Rc::new(Tensor::randn(&[2], FLOAT_CPU)),
+        encoder.clone(),
+    ];
+
+    let to = from.to_device(maybe_cuda);
+    assert!(Rc::ptr_eq(&to[0], &to[2]));
+    assert!(!Rc::ptr_eq(&to[0], &to[1]));
+    assert_eq!(to[0].device(), maybe_cuda);
+
+    let to = from.to_kind(Kind::Double);
+    assert!(Rc::ptr_eq(&to[0], &to[2]));
+    assert_eq!(to[0].kind(), Kind::Double);
+
+    let mut count = 0;
+    from.for_each_tensor(&mut |_| count += 1);
+    assert_eq!(count, 2);
+
+    // and within one alias map across values
+    let mut aliases = AliasMap::new();
+    let to = from.to_device_aliased(maybe_cuda, &mut aliases);
+    let other = encoder.to_device_aliased(maybe_cuda, &mut aliases);
+    assert!(Rc::ptr_eq(&to[0], &to[2]));
+    assert!(Rc::ptr_eq(&to[0], &other));
+
+    let steps = Arc::new(vec![1i64, 2]);
+    let to = (steps.clone(), steps).to_device(maybe_cuda);
+    assert!(Arc::ptr_eq(&to.0, &to.1));
+
+    // detaching in place keeps the shared pointers
+    let mut detached = from.clone();
+    detached.detach_();
+    assert!(Rc::ptr_eq(&detached[0], &encoder));
+    assert!(Rc::ptr_eq(&detached[2], &encoder));
+    assert!(!encoder.requires_grad());
+}
+
+#[test]
+fn into_test() {
+    let from: HashMap<String, Vec<Tensor>> =
+        vec![("images".to_string(), vec![Tensor::randn(&[2], FLOAT_CPU)])]
+            .into_iter()
+            .collect();
+    let key_ptr = from.keys().next().unwrap().as_ptr();
+
+    // keys are moved rather than cloned
+    let to = from.into_kind(Kind::Double);
+    let (key, tensors) = to.iter().next().unwrap();
+    assert_eq!(key.as_ptr(), key_ptr);
+    assert_eq!(tensors[0].kind(), Kind::Double);
+
+    let maybe_cuda = Device::cuda_if_available();
+    let from = (
+        "label".to_string(),
+        Some(Box::new(Tensor::randn(&[2], FLOAT_CPU))),
+    );
+    let label_ptr = from.0.as_ptr();
+    let to = from.into_device(maybe_cuda);
+    assert_eq!(to.0.as_ptr(), label_ptr);
+    assert_eq!(to.1.unwrap().device(), maybe_cuda);
+
+    let from: Result<Mutex<Tensor>, String> = Err("missing".to_string());
+    let err_ptr = from.as_ref().unwrap_err().as_ptr();
+    let to = from.into_kind(Kind::Double);
+    assert_eq!(to.as_ref().unwrap_err().as_ptr(), err_ptr);
+
+    let from: Result<_, String> = Ok(Mutex::new(Tensor::randn(&[2], FLOAT_CPU)));
+    let to = from.into_kind(Kind::Double);
+    assert_eq!(to.unwrap().into_inner().unwrap().kind(), Kind::Double);
+}
+
+#[test]
+fn error_path_test() {
+    let cell = || Rc::new(RefCell::new(Tensor::randn(&[2], FLOAT_CPU)));
+
+    let blocked = cell();
+    let from: HashMap<_, _> = vec![(
+        "layers".to_string(),
+        (0, vec![Some(cell()), Some(blocked.clone())]),
+    )]
+    .into_iter()
+    .collect();
+
+    // a held borrow makes the conversion of the second tensor fail
+    let guard = blocked.borrow_mut();
+    let err = from.f_to_kind(Kind::Double).unwrap_err();
+    assert_eq!(
+        err.path(),
+        [
+            PathSegment::Key(r#""layers""#.to_string()),
+            PathSegment::TupleField(1),
+            PathSegment::Index(1),
+            PathSegment::Variant("Some"),
+            PathSegment::Deref("Rc"),
+        ]
+    );
+    assert!(err
+        .to_string()
+        .starts_with(r#"at ["layers"].1[1]::Some<Rc>: cannot borrow RefCell: "#));
+
+    match TchError::from(err) {
+        TchError::Torch(msg) => assert!(msg.starts_with(r#"at ["layers"].1[1]::Some<Rc>: "#)),
+        _ => unreachable!(),
+    }
+    drop(guard);
+
+    // pointers, cells and locks record the type they wrap
+    let cells = vec![cell(), blocked.clone()];
+    let from: Result<_, ()> = Ok(Box::new(Rc::new(Mutex::new(RwLock::new(Cow::Borrowed(
+        cells.as_slice(),
+    ))))));
+    let guard = blocked.borrow_mut();
+    let err = from.f_deep_clone().unwrap_err();
+    assert_eq!(
+        err.path(),
+        [
+            PathSegment::Variant("Ok"),
+            PathSegment::Deref("Box"),
+            PathSegment::Deref("Rc"),
+            PathSegment::Deref("Mutex"),
+            PathSegment::Deref("RwLock"),
+            PathSegment::Deref("Cow"),
+            PathSegment::Index(1),
+            PathSegment::Deref("Rc"),
+        ]
+    );
+    assert!(err
+        .to_string()
+        .starts_with("at ::Ok<Box><Rc><Mutex><RwLock><Cow>[1]<Rc>: "));
+    drop(guard);
+
+    let poisoned = Arc::new(Mutex::new(Tensor::randn(&[2], FLOAT_CPU)));
+    let _ = std::thread::spawn({
+        let poisoned = poisoned.clone();
+        move || {
+            let _guard = poisoned.lock().unwrap();
+            panic!("poison the lock");
+        }
+    })
+    .join();
+    let from = Arc::new(Mutex::new(RefCell::new(vec![poisoned])));
+    let err = from.f_detach().unwrap_err();
+    assert_eq!(
+        err.path(),
+        [
+            PathSegment::Deref("Arc"),
+            PathSegment::Deref("Mutex"),
+            PathSegment::Deref("RefCell"),
+            PathSegment::Index(0),
+            PathSegment::Deref("Arc"),
+        ]
+    );
+
+    let err = TensorLikeError::new(TchError::Kind("unsupported".into()));
+    assert!(err.path().is_empty());
+    assert_eq!(
+        err.to_string(),
+        TchError::Kind("unsupported".into()).to_string()
+    );
+    assert!(matches!(err.into_tch_error(), TchError::Kind(msg) if msg == "unsupported"));
+}
+
+#[test]
+fn ivalue_test() {
+    let from = (
+        Tensor::randn(&[2], FLOAT_CPU),
+        vec![1i64, 2, 3],
+        Some(0.5f64),
+        None::<bool>,
+    );
+    let value = from.into_ivalue();
+    assert!(matches!(&value, IValue::Tuple(values) if values.len() == 4));
+
+    let (tensor, ints, double, flag) =
+        <(Tensor, Vec<i64>, Option<f64>, Option<bool>)>::try_from_ivalue(value).unwrap();
+    assert_eq!(tensor.size(), [2]);
+    assert_eq!(ints, [1, 2, 3]);
+    assert_eq!(double, Some(0.5));
+    assert_eq!(flag, None);
+
+    let map: HashMap<String, i32> = vec![("a".into(), 1), ("b".into(), 2)].into_iter().collect();
+    let to = HashMap::<String, i32>::try_from_ivalue(map.clone().into_ivalue()).unwrap();
+    assert_eq!(to, map);
+
+    // sequences of primitives and tensors become typed lists
+    assert_eq!(vec![1u8, 2].into_ivalue(), IValue::IntList(vec![1, 2]));
+    assert_eq!(vec![0.5f32].into_ivalue(), IValue::DoubleList(vec![0.5]));
+    assert_eq!(
+        [true, false].into_ivalue(),
+        IValue::BoolList(vec![true, false])
+    );
+    assert_eq!(
+        VecDeque::from(vec!["a".to_string()]).into_ivalue(),
+        IValue::StringList(vec!["a".to_string()])
+    );
+    assert_eq!(vec![Box::new(3i64)].into_ivalue(), IValue::IntList(vec![3]));
+    assert!(matches!(
+        Vec::<Tensor>::new().into_ivalue(),
+        IValue::TensorList(tensors) if tensors.is_empty()
+    ));
+    assert!(matches!(
+        vec![(1i64,)].into_ivalue(),
+        IValue::GenericList(values) if values.len() == 1
+    ));
+
+    // typed lists are accepted as well
+    let to = Vec::<i64>::try_from_ivalue(IValue::IntList(vec![4, 5])).unwrap();
+    assert_eq!(to, [4, 5]);
+    let to = <[i64; 2]>::try_from_ivalue(IValue::IntList(vec![4, 5])).unwrap();
+    assert_eq!(to, [4, 5]);
+    let to = Box::<VecDeque<bool>>::try_from_ivalue(IValue::BoolList(vec![true])).unwrap();
+    assert_eq!(*to, [true]);
+
+    assert!(<[i64; 3]>::try_from_ivalue(IValue::IntList(vec![4, 5])).is_err());
+    assert!(i8::try_from_ivalue(IValue::Int(1000)).is_err());
+    assert!(<(i64, i64)>::try_from_ivalue(IValue::Tuple(vec![IValue::Int(1)])).is_err());
+    assert!(String::try_from_ivalue(IValue::Bool(true)).is_err());
+}
+
+#[test]
+#[cfg(all(
+    feature = "smallvec",
+    feature = "arrayvec",
+    feature = "indexmap",
+    feature = "either"
+))]
+fn ivalue_container_crates_test() {
+    use arrayvec::ArrayVec;
+    use either::Either;
+    use indexmap::IndexMap;
+    use smallvec::{smallvec, SmallVec};
+
+    let from: SmallVec<[i64; 4]> = smallvec![1, 2];
+    assert_eq!(from.into_ivalue(), IValue::IntList(vec![1, 2]));
+    let to = SmallVec::<[i64; 4]>::try_from_ivalue(IValue::IntList(vec![3])).unwrap();
+    assert_eq!(to.as_slice(), [3]);
+
+    let from: ArrayVec<f64, 2> = [0.5].into_iter().collect();
+    assert_eq!(from.into_ivalue(), IValue::DoubleList(vec![0.5]));
+    assert!(ArrayVec::<f64, 2>::try_from_ivalue(IValue::DoubleList(vec![0.0; 3])).is_err());
+
+    let from: IndexMap<String, i64> = vec![("b".into(), 1), ("a".into(), 2)].into_iter().collect();
+    let to = IndexMap::<String, i64>::try_from_ivalue(from.clone().into_ivalue()).unwrap();
+    assert!(to.iter().eq(from.iter()));
+
+    let to = Either::<i64, String>::try_from_ivalue(IValue::String("a".into())).unwrap();
+    assert_eq!(to, Either::Right("a".to_string()));
+    let to = Either::<i64, String>::try_from_ivalue(IValue::Int(1)).unwrap();
+    assert_eq!(to, Either::Left(1));
+    assert_eq!(
+        Either::<i64, bool>::Right(true).into_ivalue(),
+        IValue::Bool(true)
+    );
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn ivalue_module_test() {
+    use tch::CModule;
+
+    // forward(input: Tuple[Tensor, Optional[List[int]], List[float],
+    //     List[Tensor], List[bool], List[str]]) -> Tuple[Tensor, int],
+    // which counts the list elements, see tests/fixtures/ivalue_module.py
+    #[derive(IntoIValue)]
+    struct ModelInput {
+        images: Tensor,
+        labels: Option<Vec<i64>>,
+        weights: Vec<f64>,
+        masks: Vec<Tensor>,
+        flags: Vec<bool>,
+        names: Vec<String>,
+    }
+
+    #[derive(TryFromIValue)]
+    struct ModelOutput {
+        images: Tensor,
+        count: i64,
+    }
+
+    let module = CModule::load(concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/ivalue_module.pt"
+    ))
+    .unwrap();
+    let images = Tensor::ones(&[2], FLOAT_CPU);
+
+    let input = ModelInput {
+        images: images.shallow_clone(),
+        labels: Some(vec![1, 2, 3]),
+        weights: vec![0.5],
+        masks: vec![],
+        flags: vec![true, false],
+        names: vec!["a".into()],
+    };
+    let output = module.forward_is(&[input.into_ivalue()]).unwrap();
+    let output = ModelOutput::try_from_ivalue(output).unwrap();
+    assert_eq!(output.images, &images * 2.0);
+    assert_eq!(output.count, 7);
+
+    let input = ModelInput {
+        images: images.shallow_clone(),
+        labels: None,
+        weights: vec![],
+        masks: vec![images.shallow_clone()],
+        flags: vec![],
+        names: vec![],
+    };
+    let output = module.forward_is(&[input.into_ivalue()]).unwrap();
+    assert_eq!(ModelOutput::try_from_ivalue(output).unwrap().count, 1);
+
+    // lists created as List[Any] are rejected by typed parameters
+    let input = IValue::Tuple(vec![
+        IValue::Tensor(images),
+        IValue::None,
+        IValue::GenericList(vec![IValue::Double(0.5)]),
+        IValue::TensorList(vec![]),
+        IValue::BoolList(vec![]),
+        IValue::StringList(vec![]),
+    ]);
+    assert!(module.forward_is(&[input]).is_err());
+}
+
 #[test]
 #[cfg(feature = "derive")]
 fn derive_types_test() {
@@ -403,3 +1261,667 @@ fn derive_clone_test() {
         assert_eq!(to.d, Device::Cpu);
     }
 }
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_aliasing_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    #[derive(TensorLike)]
+    struct Tied {
+        embedding: Tensor,
+        decoder: Tensor,
+        #[tensor_like(copy)]
+        device: Device,
+    }
+
+    let weight = Tensor::randn(&[4, 2], FLOAT_CPU);
+    let from = Tied {
+        embedding: weight.shallow_clone(),
+        decoder: weight.shallow_clone(),
+        device: Device::Cpu,
+    };
+    let to = from.to_device_aliased(maybe_cuda, &mut AliasMap::new());
+
+    assert_eq!(to.embedding.device(), maybe_cuda);
+    assert_eq!(to.embedding.data_ptr(), to.decoder.data_ptr());
+    assert_eq!(to.device, Device::Cpu);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_detach_test() {
+    #[derive(TensorLike)]
+    struct State(Tensor, #[tensor_like(clone)] String, Option<Tensor>);
+
+    #[derive(TensorLike)]
+    enum Hidden {
+        Lstm { h: Tensor, c: Tensor },
+        Gru(State),
+    }
+
+    let from = Hidden::Lstm {
+        h: Tensor::randn(&[], FLOAT_CPU).set_requires_grad(true),
+        c: Tensor::randn(&[], FLOAT_CPU).set_requires_grad(true),
+    };
+    match from.detach() {
+        Hidden::Lstm { h, c } => {
+            assert!(!h.requires_grad());
+            assert!(!c.requires_grad());
+        }
+        _ => unreachable!(),
+    }
+
+    let mut from = Hidden::Gru(State(
+        Tensor::randn(&[], FLOAT_CPU).set_requires_grad(true),
+        "gru".into(),
+        Some(Tensor::randn(&[], FLOAT_CPU).set_requires_grad(true)),
+    ));
+    from.detach_();
+    match from {
+        Hidden::Gru(State(tensor, name, hidden)) => {
+            assert!(!tensor.requires_grad());
+            assert_eq!(name, "gru");
+            assert!(!hidden.unwrap().requires_grad());
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_frozen_test() {
+    #[derive(TensorLike)]
+    struct Params {
+        weight: Tensor,
+        #[tensor_like(frozen)]
+        embedding: Tensor,
+        bias: Option<Tensor>,
+    }
+
+    let from = Params {
+        weight: Tensor::randn(&[2, 2], FLOAT_CPU),
+        embedding: Tensor::randn(&[4, 2], FLOAT_CPU).set_requires_grad(true),
+        bias: Some(Tensor::randn(&[2], FLOAT_CPU)),
+    };
+    let to = from.set_requires_grad(true);
+
+    assert!(to.requires_grad_any());
+    assert!(to.weight.requires_grad());
+    assert!(!to.embedding.requires_grad());
+    assert!(to.bias.unwrap().requires_grad());
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_grads_test() {
+    #[derive(TensorLike)]
+    struct Linear {
+        weight: Tensor,
+        bias: Tensor,
+        #[tensor_like(copy)]
+        out_features: i64,
+    }
+
+    let from = Linear {
+        weight: Tensor::randn(&[2], FLOAT_CPU).set_requires_grad(true),
+        bias: Tensor::randn(&[2], FLOAT_CPU).set_requires_grad(true),
+        out_features: 2,
+    };
+    (&from.weight * 3.0 + &from.bias)
+        .sum(Kind::Float)
+        .backward();
+
+    let grads = from.grads();
+    assert_eq!(grads.out_features, 2);
+    assert_abs_diff_eq!(f64::from(grads.weight.sum(Kind::Float)), 6.0);
+    assert_abs_diff_eq!(f64::from(grads.bias.sum(Kind::Float)), 2.0);
+    assert_abs_diff_eq!(
+        from.clip_grad_norm(f64::INFINITY, 2.0),
+        20f64.sqrt(),
+        epsilon = 1e-4
+    );
+
+    from.zero_grad();
+    let grads = from.grads();
+    assert_abs_diff_eq!(f64::from(grads.weight.abs().sum(Kind::Float)), 0.0);
+    assert_abs_diff_eq!(f64::from(grads.bias.abs().sum(Kind::Float)), 0.0);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_deep_clone_test() {
+    #[derive(TensorLike)]
+    struct Snapshot {
+        state: Tensor,
+        #[tensor_like(clone)]
+        name: String,
+    }
+
+    let from = Snapshot {
+        state: Tensor::randn(&[2], FLOAT_CPU),
+        name: "snapshot".into(),
+    };
+    let to = from.deep_clone();
+
+    assert_ne!(from.state.data_ptr(), to.state.data_ptr());
+    assert_abs_diff_eq!(
+        f64::from((&from.state - &to.state).abs().sum(Kind::Float)),
+        0.0
+    );
+    assert_eq!(to.name, "snapshot");
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_float_kind_test() {
+    #[derive(TensorLike)]
+    struct Batch {
+        images: Tensor,
+        labels: Tensor,
+        indexes: Option<Tensor>,
+    }
+
+    let from = Batch {
+        images: Tensor::randn(&[2, 3], FLOAT_CPU),
+        labels: Tensor::zeros(&[2], INT64_CPU),
+        indexes: Some(Tensor::zeros(&[2], (Kind::Int, Device::Cpu))),
+    };
+    let to = from.to_float_kind(Kind::Double);
+
+    assert_eq!(to.images.kind(), Kind::Double);
+    assert_eq!(to.labels.kind(), Kind::Int64);
+    assert_eq!(to.indexes.unwrap().kind(), Kind::Int);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_kind_attr_test() {
+    #[derive(TensorLike)]
+    struct Batch {
+        images: Tensor,
+        #[tensor_like(kind = "keep")]
+        mask: Tensor,
+        #[tensor_like(kind = "Float")]
+        loss_weights: Vec<Tensor>,
+        #[tensor_like(kind = "Int64")]
+        labels: Tensor,
+    }
+
+    let from = Batch {
+        images: Tensor::randn(&[2, 3], FLOAT_CPU),
+        mask: Tensor::randn(&[2, 3], FLOAT_CPU),
+        loss_weights: vec![Tensor::randn(&[2], FLOAT_CPU)],
+        labels: Tensor::zeros(&[2], (Kind::Int, Device::Cpu)),
+    };
+
+    let to = from.to_kind(Kind::Half);
+    assert_eq!(to.images.kind(), Kind::Half);
+    assert_eq!(to.mask.kind(), Kind::Float);
+    assert_eq!(to.loss_weights[0].kind(), Kind::Float);
+    assert_eq!(to.labels.kind(), Kind::Int64);
+
+    // fixed kinds still leave non-floating point tensors untouched
+    let to = from.to_float_kind(Kind::Half);
+    assert_eq!(to.images.kind(), Kind::Half);
+    assert_eq!(to.mask.kind(), Kind::Float);
+    assert_eq!(to.loss_weights[0].kind(), Kind::Float);
+    assert_eq!(to.labels.kind(), Kind::Int);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_device_attr_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    #[derive(TensorLike)]
+    struct Model {
+        weight: Tensor,
+        #[tensor_like(device = "cpu")]
+        lookup_table: Tensor,
+        #[tensor_like(no_device)]
+        counter: Tensor,
+    }
+
+    let from = Model {
+        weight: Tensor::randn(&[2], FLOAT_CPU),
+        lookup_table: Tensor::randn(&[16], FLOAT_CPU),
+        counter: Tensor::zeros(&[], INT64_CPU),
+    };
+
+    let to = from.to_device(maybe_cuda);
+    assert_eq!(to.weight.device(), maybe_cuda);
+    assert_eq!(to.lookup_table.device(), Device::Cpu);
+    assert_eq!(to.counter.device(), Device::Cpu);
+
+    let to = to.to_device_aliased(Device::Cpu, &mut AliasMap::new());
+    assert_eq!(to.weight.device(), Device::Cpu);
+    assert_eq!(to.lookup_table.device(), Device::Cpu);
+    assert_eq!(to.counter.device(), Device::Cpu);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_skip_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    #[derive(Default)]
+    struct Cache(Vec<String>);
+
+    type Activation = Box<dyn Fn(&Tensor) -> Tensor>;
+
+    fn default_activation() -> Activation {
+        Box::new(|xs| xs.relu())
+    }
+
+    #[derive(TensorLike)]
+    struct Layer {
+        weight: Tensor,
+        #[tensor_like(skip)]
+        cache: Cache,
+        #[tensor_like(default = "default_activation")]
+        activation: Activation,
+    }
+
+    #[derive(TensorLike)]
+    enum Wrapper {
+        Layer(Layer, #[tensor_like(skip)] Option<Cache>),
+    }
+
+    let from = Wrapper::Layer(
+        Layer {
+            weight: Tensor::randn(&[2], FLOAT_CPU),
+            cache: Cache(vec!["cached".into()]),
+            activation: Box::new(|xs| xs.tanh()),
+        },
+        Some(Cache::default()),
+    );
+
+    let mut to = from.to_device(maybe_cuda).to_kind(Kind::Double);
+    to.detach_();
+
+    match to {
+        Wrapper::Layer(layer, cache) => {
+            assert_eq!(layer.weight.device(), maybe_cuda);
+            assert_eq!(layer.weight.kind(), Kind::Double);
+            assert!(layer.cache.0.is_empty());
+            assert!(cache.is_none());
+
+            let xs = Tensor::of_slice(&[-1.0f32, 1.0]);
+            assert_abs_diff_eq!(f64::from((layer.activation)(&xs).sum(Kind::Float)), 1.0);
+        }
+    }
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_with_test() {
+    let maybe_cuda = Device::cuda_if_available();
+
+    mod foreign {
+        use tch::{TchError, Tensor};
+        use tch_tensor_like::TensorMapper;
+
+        // stands for a type from another crate that cannot implement TensorLike
+        pub struct Foreign {
+            pub inner: Tensor,
+        }
+
+        pub fn f_map_tensors(
+            value: &Foreign,
+            mapper: &mut TensorMapper<'_>,
+        ) -> Result<Foreign, TchError> {
+            Ok(Foreign {
+                inner: mapper.f_map(&value.inner)?,
+            })
+        }
+    }
+    use foreign::Foreign;
+
+    #[derive(TensorLike)]
+    struct Model {
+        weight: Tensor,
+        #[tensor_like(with = "foreign")]
+        foreign: Foreign,
+        #[tensor_like(with = "foreign", kind = "keep")]
+        kept: Foreign,
+    }
+
+    let from = Model {
+        weight: Tensor::randn(&[2], FLOAT_CPU),
+        foreign: Foreign {
+            inner: Tensor::randn(&[2], FLOAT_CPU),
+        },
+        kept: Foreign {
+            inner: Tensor::randn(&[2], FLOAT_CPU),
+        },
+    };
+    let to = from.to_device(maybe_cuda).to_kind(Kind::Double);
+
+    assert_eq!(to.weight.device(), maybe_cuda);
+    assert_eq!(to.weight.kind(), Kind::Double);
+    assert_eq!(to.foreign.inner.device(), maybe_cuda);
+    assert_eq!(to.foreign.inner.kind(), Kind::Double);
+    assert_eq!(to.kept.inner.device(), maybe_cuda);
+    assert_eq!(to.kept.inner.kind(), Kind::Float);
+
+    // every other method goes through the module as well
+    let copy = from.deep_clone();
+    assert_ne!(copy.foreign.inner.data_ptr(), from.foreign.inner.data_ptr());
+    assert_abs_diff_eq!(
+        f64::from(
+            (&copy.foreign.inner - &from.foreign.inner)
+                .abs()
+                .sum(Kind::Float)
+        ),
+        0.0
+    );
+
+    let params = from.set_requires_grad(true);
+    assert!(params.foreign.inner.requires_grad());
+    assert!(params.kept.inner.requires_grad());
+
+    let mut count = 0;
+    to.for_each_tensor(&mut |_| count += 1);
+    assert_eq!(count, 3);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_crate_path_test() {
+    mod facade {
+        pub use tch;
+        pub use tch_tensor_like;
+    }
+
+    #[derive(TensorLike)]
+    #[tensor_like(crate = "facade::tch_tensor_like", tch = "facade::tch")]
+    struct Input {
+        images: Tensor,
+        #[tensor_like(kind = "Int64", device = "cpu")]
+        labels: Tensor,
+    }
+
+    let from = Input {
+        images: Tensor::randn(&[2], FLOAT_CPU),
+        labels: Tensor::randn(&[2], FLOAT_CPU),
+    };
+    let to = from.to_kind(Kind::Double).to_device(Device::Cpu);
+
+    assert_eq!(to.images.kind(), Kind::Double);
+    assert_eq!(to.labels.kind(), Kind::Int64);
+    assert_eq!(to.labels.device(), Device::Cpu);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_bound_test() {
+    trait Backend {}
+
+    struct Cpu;
+    impl Backend for Cpu {}
+
+    // B only appears in PhantomData and K is a map key, thus neither is
+    // bounded by TensorLike
+    #[derive(TensorLike)]
+    struct Batch<B: Backend, K, T> {
+        items: Vec<T>,
+        named: HashMap<K, T>,
+        backend: PhantomData<B>,
+    }
+
+    let from = Batch::<Cpu, String, Tensor> {
+        items: vec![Tensor::randn(&[2], FLOAT_CPU)],
+        named: vec![("label".to_string(), Tensor::randn(&[2], FLOAT_CPU))]
+            .into_iter()
+            .collect(),
+        backend: PhantomData,
+    };
+    let to = from.to_kind(Kind::Double);
+    assert_eq!(to.items[0].kind(), Kind::Double);
+    assert_eq!(to.named["label"].kind(), Kind::Double);
+
+    // E is the error of a Result, thus only bounded by Clone
+    #[derive(Debug, Clone, PartialEq)]
+    struct Missing;
+
+    #[derive(TensorLike)]
+    struct Fallible<T, E> {
+        outputs: Vec<Result<T, E>>,
+    }
+
+    let from = Fallible {
+        outputs: vec![Ok(Tensor::randn(&[2], FLOAT_CPU)), Err(Missing)],
+    };
+    let to = from.to_kind(Kind::Double);
+    assert_eq!(to.outputs[0].as_ref().unwrap().kind(), Kind::Double);
+    assert_eq!(to.outputs[1].as_ref().unwrap_err(), &Missing);
+
+    // custom container bound
+    #[derive(TensorLike)]
+    #[tensor_like(bound = "T: TensorLike")]
+    struct Wrapper<T> {
+        inner: Option<T>,
+    }
+
+    let from = Wrapper {
+        inner: Some(Tensor::randn(&[2], FLOAT_CPU)),
+    };
+    let to = from.to_kind(Kind::Double);
+    assert_eq!(to.inner.unwrap().kind(), Kind::Double);
+
+    // custom field bound
+    #[derive(TensorLike)]
+    struct Labeled<L, T> {
+        value: T,
+        #[tensor_like(clone, bound = "L: Clone")]
+        label: L,
+    }
+
+    let from = Labeled {
+        value: Tensor::randn(&[2], FLOAT_CPU),
+        label: "weight".to_string(),
+    };
+    let to = from.to_kind(Kind::Double);
+    assert_eq!(to.value.kind(), Kind::Double);
+    assert_eq!(to.label, "weight");
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_array_test() {
+    #[derive(TensorLike)]
+    struct MultiView {
+        views: [Tensor; 3],
+        #[tensor_like(copy)]
+        weights: [f32; 3],
+    }
+
+    let from = MultiView {
+        views: [
+            Tensor::randn(&[2], FLOAT_CPU),
+            Tensor::randn(&[2], FLOAT_CPU),
+            Tensor::randn(&[2], FLOAT_CPU),
+        ],
+        weights: [0.2, 0.3, 0.5],
+    };
+    let to = from.to_kind(Kind::Double);
+
+    assert!(to.views.iter().all(|view| view.kind() == Kind::Double));
+    assert_eq!(to.weights, [0.2, 0.3, 0.5]);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_recursive_test() {
+    #[derive(TensorLike)]
+    enum Tree {
+        Leaf(Tensor),
+        Node(Box<Tree>, Box<Tree>),
+    }
+
+    let from = Tree::Node(
+        Box::new(Tree::Leaf(Tensor::randn(&[2], FLOAT_CPU))),
+        Box::new(Tree::Leaf(Tensor::randn(&[2], FLOAT_CPU))),
+    );
+    let to = from.to_kind(Kind::Double);
+
+    let mut count = 0;
+    to.for_each_tensor(&mut |tensor| {
+        assert_eq!(tensor.kind(), Kind::Double);
+        count += 1;
+    });
+    assert_eq!(count, 2);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_ivalue_test() {
+    trait Backend {}
+
+    struct Cpu;
+    impl Backend for Cpu {}
+
+    #[derive(Debug, PartialEq, IntoIValue, TryFromIValue)]
+    struct Output(Tensor, Vec<i64>);
+
+    // B only appears in PhantomData, which is skipped and not bounded
+    #[derive(IntoIValue, TryFromIValue)]
+    #[ivalue(dict)]
+    struct Input<B: Backend, T> {
+        images: Tensor,
+        #[ivalue(rename = "label_ids")]
+        labels: Option<Vec<T>>,
+        #[ivalue(skip)]
+        cache: Vec<f64>,
+        backend: PhantomData<B>,
+    }
+
+    let tensor = Tensor::randn(&[2], FLOAT_CPU);
+
+    let from = Output(tensor.shallow_clone(), vec![1, 2]);
+    let value = from.into_ivalue();
+    assert!(matches!(&value, IValue::Tuple(values) if values.len() == 2));
+    let to = Output::try_from_ivalue(value).unwrap();
+    assert_eq!(to, Output(tensor.shallow_clone(), vec![1, 2]));
+
+    let from = Input::<Cpu, i64> {
+        images: tensor.shallow_clone(),
+        labels: Some(vec![3, 4]),
+        cache: vec![0.5],
+        backend: PhantomData,
+    };
+    let value = from.into_ivalue();
+    match &value {
+        IValue::GenericDict(entries) => {
+            let keys: Vec<_> = entries
+                .iter()
+                .map(|(key, _)| match key {
+                    IValue::String(key) => key.as_str(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            assert_eq!(keys, ["images", "label_ids"]);
+            assert_eq!(entries[1].1, IValue::IntList(vec![3, 4]));
+        }
+        _ => unreachable!(),
+    }
+
+    let to = Input::<Cpu, i64>::try_from_ivalue(value).unwrap();
+    assert_eq!(to.images, tensor);
+    assert_eq!(to.labels, Some(vec![3, 4]));
+    assert!(to.cache.is_empty());
+
+    let missing = IValue::GenericDict(vec![(
+        IValue::String("images".into()),
+        IValue::Tensor(tensor),
+    )]);
+    assert!(Input::<Cpu, i64>::try_from_ivalue(missing).is_err());
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_error_path_test() {
+    #[derive(Debug, TensorLike)]
+    struct Layer {
+        weight: RefCell<Tensor>,
+    }
+
+    #[derive(Debug, TensorLike)]
+    enum Block {
+        Single(Layer),
+        Pair { first: Layer, r#second: Layer },
+    }
+
+    let layer = || Layer {
+        weight: RefCell::new(Tensor::randn(&[2], FLOAT_CPU)),
+    };
+
+    let from = Block::Pair {
+        first: layer(),
+        second: layer(),
+    };
+    let guard = match &from {
+        Block::Pair { second, .. } => second.weight.borrow_mut(),
+        Block::Single(_) => unreachable!(),
+    };
+    let err = from.f_to_kind(Kind::Double).unwrap_err();
+    assert_eq!(
+        err.path(),
+        [
+            PathSegment::Variant("Pair"),
+            PathSegment::Field("second"),
+            PathSegment::Field("weight"),
+        ]
+    );
+    assert!(err.to_string().starts_with("at ::Pair.second.weight: "));
+    drop(guard);
+
+    let from = Block::Single(layer());
+    let guard = match &from {
+        Block::Single(layer) => layer.weight.borrow_mut(),
+        Block::Pair { .. } => unreachable!(),
+    };
+    let err = from.f_deep_clone().unwrap_err();
+    assert_eq!(
+        err.path(),
+        [
+            PathSegment::Variant("Single"),
+            PathSegment::TupleField(0),
+            PathSegment::Field("weight"),
+        ]
+    );
+    drop(guard);
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn derive_into_test() {
+    #[derive(TensorLike)]
+    struct Input {
+        images: Tensor,
+        #[tensor_like(clone)]
+        names: Vec<String>,
+        #[tensor_like(kind = "keep")]
+        labels: Tensor,
+    }
+
+    let from = Input {
+        images: Tensor::randn(&[2], FLOAT_CPU),
+        names: vec!["a".to_string(), "b".to_string()],
+        labels: Tensor::zeros(&[2], INT64_CPU),
+    };
+    let names_ptr = from.names.as_ptr();
+
+    // cloned fields are moved
+    let to = from.into_kind(Kind::Double);
+    assert_eq!(to.images.kind(), Kind::Double);
+    assert_eq!(to.labels.kind(), Kind::Int64);
+    assert_eq!(to.names.as_ptr(), names_ptr);
+
+    let maybe_cuda = Device::cuda_if_available();
+    let to = to.into_device(maybe_cuda);
+    assert_eq!(to.images.device(), maybe_cuda);
+    assert_eq!(to.labels.device(), maybe_cuda);
+    assert_eq!(to.names.as_ptr(), names_ptr);
+}
@@ -0,0 +1,76 @@
+#!/usr/bin/env python3
+"""Writes ivalue_module.pt, a TorchScript module used by ivalue_module_test.
+
+The archive is assembled by hand so that generating it does not require
+PyTorch. It is equivalent to saving the following module with
+torch.jit.save(torch.jit.script(IValueModule()), "ivalue_module.pt").
+
+    class IValueModule(torch.nn.Module):
+        def forward(
+            self,
+            input: Tuple[Tensor, Optional[List[int]], List[float], List[Tensor],
+                         List[bool], List[str]],
+        ) -> Tuple[Tensor, int]:
+            ...
+"""
+
+import os
+import struct
+import zipfile
+
+CODE = """\
+class IValueModule(Module):
+  __parameters__ = []
+  __buffers__ = []
+  training : bool
+  def forward(self: __torch__.IValueModule,
+    input: Tuple[Tensor, Optional[List[int]], List[float], List[Tensor], List[bool], List[str]]) -> Tuple[Tensor, int]:
+    images, labels, weights, masks, flags, names = input
+    count = len(weights) + len(masks) + len(flags) + len(names)
+    if labels is not None:
+      count = count + len(labels)
+    return (torch.mul(images, 2.0), count)
+"""
+
+
+def pickle_string(value):
+    data = value.encode("utf-8")
+    return b"X" + struct.pack("<I", len(data)) + data
+
+
+# pickle protocol 2 of the module object, whose only attribute is training
+DATA = (
+    b"\x80\x02"  # PROTO 2
+    + b"c__torch__\nIValueModule\n"  # GLOBAL
+    + b")"  # EMPTY_TUPLE
+    + b"\x81"  # NEWOBJ
+    + b"}"  # EMPTY_DICT
+    + pickle_string("training")
+    + b"\x88"  # NEWTRUE
+    + b"s"  # SETITEM
+    + b"b"  # BUILD
+    + b"."  # STOP
+)
+
+# pickle protocol 2 of an empty tuple
+CONSTANTS = b"\x80\x02)."
+
+RECORDS = [
+    ("version", b"3\n"),
+    ("data.pkl", DATA),
+    ("code/__torch__.py", CODE.encode("utf-8")),
+    ("constants.pkl", CONSTANTS),
+]
+
+
+def main():
+    path = os.path.join(os.path.dirname(os.path.abspath(__file__)), "ivalue_module.pt")
+
+    with zipfile.ZipFile(path, "w", zipfile.ZIP_STORED) as archive:
+        for name, data in RECORDS:
+            info = zipfile.ZipInfo("ivalue_module/" + name, date_time=(1980, 1, 1, 0, 0, 0))
+            archive.writestr(info, data)
+
+
+if __name__ == "__main__":
+    main()